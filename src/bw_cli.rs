@@ -1,43 +1,194 @@
+use crate::config::{Account, LoginMethod, Scope, CONFIG};
+use crate::gui::Credentials;
+use crate::tray::Update;
 use crate::ActiveWindowInfo;
-use anyhow::{bail, Context, Result};
-use chrono::{DateTime, Utc};
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, SecondsFormat, Utc};
 use lazy_static::lazy_static;
 use log::error;
 use log::info;
+use log::warn;
 use parking_lot::RwLock;
 use serde::Deserialize;
-use serde_repr::*;
+use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
 use std::fmt::{Display, Formatter};
+use std::io::Write;
+#[cfg(windows)]
 use std::os::windows::process::CommandExt;
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+static DEFAULT_SERVER: &str = "https://vault.bitwarden.com";
+static SSH_PASSPHRASE_FIELD: &str = "passphrase";
+// Written in front of patterns kept in an item's notes
+static PATTERN_INDICATOR: &str = "Autotype: ";
+// As many old passwords as Bitwarden's apps keep
+static PASSWORD_HISTORY_LENGTH: usize = 5;
+// Hands bw the master password through its environment, for `--passwordenv`
+static PASSWORD_ENV: &str = "BW_PASSWORD";
+
+struct Session {
+    // Zeroizing wipes the key from memory once it's replaced or dropped
+    key: Zeroizing<String>,
+    // Lets items that ask for the master password again check it without calling bw
+    password_hash: String,
+    unlocked_at: Instant,
+    last_used: Instant,
+}
+
+impl Session {
+    // Why the session has expired, if it has
+    fn expiry(&self, idle_limit: Option<Duration>, lifetime: Option<Duration>) -> Option<String> {
+        match (idle_limit, lifetime) {
+            (Some(limit), _) if self.last_used.elapsed() >= limit => {
+                Some(format!("idle for {} min", limit.as_secs() / 60))
+            }
+            (_, Some(limit)) if self.unlocked_at.elapsed() >= limit => {
+                Some(format!("unlocked for {} min", limit.as_secs() / 60))
+            }
+            _ => None,
+        }
+    }
+}
 
 lazy_static! {
-    static ref SESSION_KEY: RwLock<Option<String>> = RwLock::new(None);
-    pub static ref EMAIL: RwLock<Option<String>> = RwLock::new(None);
+    // The sessions of all unlocked accounts, by account name
+    static ref SESSIONS: RwLock<HashMap<String, Session>> = RwLock::new(HashMap::new());
+    static ref NAMES: RwLock<HashMap<String, Names>> = RwLock::new(HashMap::new());
+    // SSH keys have no URLs bw could search by, so they are listed once per account
+    static ref SSH_KEYS: RwLock<HashMap<String, Vec<LoginItem>>> = RwLock::new(HashMap::new());
+    // A line of an item's notes holding a pattern, like `Autotype: {PASSWORD}{ENTER}`, or
+    // `Autotype[title~=.*Cisco.*]: {PASSWORD}{ENTER}` for some windows only
+    static ref PATTERN_LINE: regex::Regex =
+        regex::Regex::new(r"(?i)^\s*autotype\s*(?:\[(.*?)\])?\s*:\s*(.*)$").unwrap();
 }
 
-pub fn login() -> Result<()> {
-    let status = status().context("Failed to get status")?;
+/// Logs into or unlocks the account. Returns `false` if the user cancelled.
+pub fn login(account: &Account) -> Result<bool> {
+    let status = status(account).context("Failed to get status")?;
+    let mut logged_in = status.vault_status != VaultStatus::Unauthenticated;
+
+    // Unlocking with the master password of an account on a different server than the one
+    // configured would at best fail, so don't even ask for it
+    if let (true, Some(configured)) = (logged_in, &account.server_url) {
+        if !same_server(status.server_url.as_deref(), configured) {
+            bail!(
+                "{} is logged into {}, but configured for {}. Log out to switch servers.",
+                account.name,
+                status.server_url.as_deref().unwrap_or(DEFAULT_SERVER),
+                configured
+            );
+        }
+    }
+    let server_url = if logged_in {
+        status.server_url.clone()
+    } else {
+        account
+            .server_url
+            .clone()
+            .or_else(|| status.server_url.clone())
+    };
+    let mut method = account.login_method;
 
     loop {
-        let (email, password) = crate::gui::prompt_bw_login(status.user_email.clone())?;
+        let defaults = Credentials {
+            method,
+            email: status
+                .user_email
+                .clone()
+                .or_else(|| account.email.clone())
+                .unwrap_or_default(),
+            client_id: account.client_id.clone().unwrap_or_default(),
+            server_url: server_url.clone().unwrap_or_default(),
+            identity_url: account.identity_url.clone().unwrap_or_default(),
+            api_url: account.api_url.clone().unwrap_or_default(),
+            notifications_url: account.notifications_url.clone().unwrap_or_default(),
+            ..Credentials::default()
+        };
+        let mut credentials = match crate::gui::prompt_bw_login(&account.name, defaults, logged_in)?
+        {
+            Some(credentials) => credentials,
+            None => {
+                info!("Login cancelled");
+                return Ok(false);
+            }
+        };
+        method = credentials.method;
+        let password = std::mem::take(&mut credentials.password);
         if password.trim().is_empty() {
-            bail!("Password with len 0? This can't be right, aborting before 'bw login' stalls")
+            // 'bw login' would stall waiting for a password, so just ask again
+            continue;
         }
+        // Passed through the environment, so it doesn't show up in process lists
+        let password_env = [(PASSWORD_ENV, password.as_str())];
 
-        let output = if VaultStatus::Unauthenticated == status.vault_status {
-            info!("Logging in...");
-            call_bw(vec!["login", "--raw", email.trim(), &password])
+        let output = if !logged_in && method == LoginMethod::Password {
+            configure_server(account, &credentials)?;
+            info!("Logging into {}...", account.name);
+            let args = vec![
+                "login",
+                "--raw",
+                credentials.email.trim(),
+                "--passwordenv",
+                PASSWORD_ENV,
+            ];
+            match call_bw_with(account, args.clone(), &password_env, None) {
+                Err(CliError::TwoStepRequired { new_device }) => {
+                    info!("{} requires two-step login", account.name);
+                    match two_step_login(account, &args, &password_env, new_device)? {
+                        Some(key) => Ok(key),
+                        None => {
+                            info!("Login cancelled");
+                            return Ok(false);
+                        }
+                    }
+                }
+                output => output,
+            }
         } else {
-            info!("Already logged in, unlocking vault...");
-            call_bw(vec!["unlock", "--raw", &password])
+            if !logged_in {
+                configure_server(account, &credentials)?;
+                if method == LoginMethod::ApiKey {
+                    info!("Logging into {} with API key...", account.name);
+                    let env = [
+                        ("BW_CLIENTID", credentials.client_id.trim()),
+                        ("BW_CLIENTSECRET", credentials.client_secret.trim()),
+                    ];
+                    call_bw_with(account, vec!["login", "--apikey"], &env, None)
+                        .context("Logging in with the API key failed")?;
+                } else {
+                    info!(
+                        "Logging into {} with SSO, continuing in the browser...",
+                        account.name
+                    );
+                    call_bw(account, vec!["login", "--sso"]).context("SSO login failed")?;
+                }
+                // These methods only log in, unlocking still needs the master password.
+                // Should that fail, the next attempt only has to unlock.
+                logged_in = true;
+            }
+            info!("Logged into {}, unlocking vault...", account.name);
+            call_bw_with(
+                account,
+                vec!["unlock", "--raw", "--passwordenv", PASSWORD_ENV],
+                &password_env,
+                None,
+            )
         };
 
         match output {
             Err(e) => {
                 match e {
                     CliError::InvalidPassword => {} // loop and ask again
+                    CliError::TwoStepRequired { .. } | CliError::InvalidCode => {
+                        bail!("Two-step login failed")
+                    }
                     CliError::FailedToRun(io) => {
                         bail!("Calling bw failed: {:#?}", io)
                     }
@@ -47,41 +198,148 @@ pub fn login() -> Result<()> {
                 }
             }
             Ok(key) => {
-                let mut guard = SESSION_KEY.write();
-                *guard = Some(key);
-                drop(guard);
+                start_session(account, key, hash_master_password(&password)?);
+                info!("Acquired session key for {}", account.name);
 
-                let mut guard = EMAIL.write();
-                *guard = Some(email);
-                drop(guard);
-
-                return Ok(());
+                crate::tray::refresh();
+                return Ok(true);
             }
         }
-        info!("Acquired session key");
     }
 }
-#[derive(Debug, Deserialize, PartialEq)]
+
+/// Second factors bw can log in with, as `bw login --method` numbers them.
+/// FIDO2 and Duo need a browser, which bw doesn't support.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TwoStepMethod {
+    Authenticator = 0,
+    Email = 1,
+    YubiKey = 3,
+}
+
+impl TwoStepMethod {
+    pub const ALL: [TwoStepMethod; 3] = [
+        TwoStepMethod::Authenticator,
+        TwoStepMethod::Email,
+        TwoStepMethod::YubiKey,
+    ];
+}
+
+impl Display for TwoStepMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TwoStepMethod::Authenticator => "Authenticator app",
+            TwoStepMethod::Email => "Email",
+            TwoStepMethod::YubiKey => "YubiKey OTP",
+        })
+    }
+}
+
+/// Finishes a login that needs a second factor, which bw can't ask for itself since it isn't
+/// run interactively. Returns `None` if the user cancelled.
+fn two_step_login(
+    account: &Account,
+    login_args: &[&str],
+    env: &[(&str, &str)],
+    new_device: bool,
+) -> Result<Option<String>> {
+    // New device verification always sends its code by email, there is nothing to choose
+    let method = if new_device {
+        None
+    } else {
+        match crate::gui::choose_two_step_method(&account.name) {
+            Some(method) => Some(method),
+            None => return Ok(None),
+        }
+    };
+    let method_id = method.map(|m| (m as u8).to_string());
+    let mut args = login_args.to_vec();
+    if let Some(id) = &method_id {
+        args.extend(&["--method", id.as_str()]);
+    }
+
+    if method == Some(TwoStepMethod::Email) {
+        // Without a code, bw has the server send one and then complains that it's missing
+        match call_bw_with(account, args.clone(), env, None) {
+            Err(CliError::TwoStepRequired { .. }) => {}
+            output => return Ok(Some(output?)),
+        }
+    }
+
+    let prompt = match method {
+        Some(TwoStepMethod::Email) => "Enter the code that was emailed to you".to_string(),
+        Some(method) => format!("Enter the code from your {}", method),
+        None => "This device is new to Bitwarden, enter the code that was emailed to you".into(),
+    };
+    loop {
+        let code = match crate::gui::prompt_code(&prompt) {
+            Some(code) if !code.trim().is_empty() => code,
+            Some(_) => continue,
+            None => return Ok(None),
+        };
+        let mut code_args = args.clone();
+        code_args.extend(&["--code", code.trim()]);
+        match call_bw_with(account, code_args, env, None) {
+            Err(CliError::InvalidCode) => info!("Invalid two-step code, asking again"),
+            output => return Ok(Some(output?)),
+        }
+    }
+}
+
+/// Points bw at the server, along with any separately entered service URLs.
+fn configure_server(account: &Account, credentials: &Credentials) -> Result<()> {
+    let args = server_args(credentials);
+    info!("Using server {} for {}", args[2], account.name);
+    call_bw(account, args).context("Failed to configure server")?;
+    Ok(())
+}
+
+fn server_args(credentials: &Credentials) -> Vec<&str> {
+    let url = match credentials.server_url.trim() {
+        "" => DEFAULT_SERVER,
+        url => url,
+    };
+    let mut args = vec!["config", "server", url];
+    let services = [
+        ("--identity", &credentials.identity_url),
+        ("--api", &credentials.api_url),
+        ("--notifications", &credentials.notifications_url),
+    ];
+    for &(flag, service_url) in &services {
+        if !service_url.trim().is_empty() {
+            args.extend(&[flag, service_url.trim()]);
+        }
+    }
+    args
+}
+
+// bw reports no server URL at all until one has been configured
+fn same_server(stored: Option<&str>, configured: &str) -> bool {
+    let normalize = |url: &str| url.trim().trim_end_matches('/').to_lowercase();
+    normalize(stored.unwrap_or(DEFAULT_SERVER)) == normalize(configured)
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
-enum VaultStatus {
+pub enum VaultStatus {
     Unlocked,
     Locked,
     Unauthenticated,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Status {
-    server_url: String,
-    last_sync: Option<DateTime<Utc>>,
-    user_email: Option<String>,
-    user_id: Option<String>,
+    /// None means the official bitwarden.com server
+    pub server_url: Option<String>,
+    pub last_sync: Option<DateTime<Utc>>,
+    pub user_email: Option<String>,
     #[serde(rename = "status")]
-    vault_status: VaultStatus,
+    pub vault_status: VaultStatus,
 }
 
-pub fn status() -> Result<Status> {
-    let stdout = call_bw(vec!["status"])?;
+pub fn status(account: &Account) -> Result<Status> {
+    let stdout = call_bw(account, vec!["status"])?;
     let status: Status = serde_json::from_str(&stdout).context("Failed to serialize status")?;
     Ok(status)
 }
@@ -95,17 +353,154 @@ pub struct LoginItem {
     pub name: String,
     pub notes: Option<String>,
     pub login: Option<Login>,
+    pub ssh_key: Option<SshKey>,
+    #[serde(default)]
+    pub fields: Vec<Field>,
+    /// 1 if the master password has to be entered again before using the item
+    #[serde(default)]
+    pub reprompt: u8,
+    pub folder_id: Option<String>,
+    pub organization_id: Option<String>,
+    #[serde(default)]
+    pub collection_ids: Vec<String>,
+    /// The account this item was read from
+    #[serde(skip)]
+    pub account: Account,
+    /// Names for the ids above, filled in by `list_logins`
+    #[serde(skip)]
+    pub folder: Option<String>,
+    #[serde(skip)]
+    pub collections: Vec<String>,
+    #[serde(skip)]
+    pub organization: Option<String>,
 }
 
 impl LoginItem {
-    pub fn autotype_pattern(&self) -> Option<String> {
-        let indicator = "Autotype: ";
-        let mut lines = self.notes.as_ref()?.lines();
-        lines.find_map(|l| l.strip_prefix(indicator).map(|s| s.to_string()))
+    /// The item's own pattern for the window: the first one whose selector matches it, or else
+    /// the one without a selector. Pattern fields come before `Autotype:` lines in the notes.
+    /// Without a window, only the one without a selector counts.
+    pub fn autotype_pattern(&self, window: Option<&ActiveWindowInfo>) -> Option<String> {
+        let patterns = self.patterns();
+        window
+            .and_then(|w| {
+                patterns
+                    .iter()
+                    .find(|(selector, _)| matches!(selector, Some(s) if s.matches(w)))
+            })
+            .or_else(|| patterns.iter().find(|(selector, _)| selector.is_none()))
+            .map(|(_, pattern)| pattern.clone())
+    }
+
+    /// Whether one of the item's patterns has a selector matching the window. That's how SSH
+    /// keys, which have no URLs, are matched.
+    pub fn selects(&self, window: &ActiveWindowInfo) -> bool {
+        self.patterns()
+            .iter()
+            .any(|(selector, _)| matches!(selector, Some(s) if s.matches(window)))
+    }
+
+    // All of the item's patterns with their selectors, in the order they are tried
+    fn patterns(&self) -> Vec<(Option<WindowSelector>, String)> {
+        let field_name = CONFIG.read().pattern_field.clone();
+        let from_fields = self.fields.iter().filter_map(|f| {
+            let name = f.name.as_deref()?.trim();
+            // `autotype`, or `autotype[...]` with a selector
+            let rest = name
+                .get(..field_name.len())
+                .filter(|start| start.eq_ignore_ascii_case(&field_name))
+                .map(|_| &name[field_name.len()..])?;
+            let selector = match rest {
+                "" => None,
+                _ => Some(rest.strip_prefix('[')?.strip_suffix(']')?),
+            };
+            Some((selector, f.value.clone()?))
+        });
+        let notes = self.notes.as_deref().unwrap_or_default();
+        let from_notes = notes.lines().filter_map(|l| {
+            let captures = PATTERN_LINE.captures(l)?;
+            let selector = captures.get(1).map(|m| m.as_str());
+            Some((selector, captures[2].to_string()))
+        });
+
+        let mut patterns = vec![];
+        for (selector, pattern) in from_fields.chain(from_notes) {
+            if pattern.trim().is_empty() {
+                continue;
+            }
+            match selector.map(str::parse::<WindowSelector>).transpose() {
+                Ok(selector) => patterns.push((selector, pattern)),
+                Err(e) => warn!("Ignoring a pattern of {}: {:#}", self.name, e),
+            }
+        }
+        patterns
+    }
+
+    // Patterns saved before there was a pattern field stay in the notes
+    fn has_pattern_line(&self) -> bool {
+        let notes = self.notes.as_deref().unwrap_or_default();
+        notes.lines().any(is_plain_pattern_line)
+    }
+
+    /// The item's own pattern for the window, or the configured default for its type.
+    pub fn effective_pattern(&self, window: Option<&ActiveWindowInfo>) -> String {
+        self.autotype_pattern(window).unwrap_or_else(|| {
+            let config = CONFIG.read();
+            match self.item_type {
+                ItemType::SshKey => config.default_ssh_pattern.clone(),
+                _ => config.default_pattern.clone(),
+            }
+        })
+    }
+
+    pub fn needs_reprompt(&self) -> bool {
+        self.reprompt == 1
+    }
+
+    pub fn username(&self) -> String {
+        self.login
+            .as_ref()
+            .and_then(|l| l.username.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn uris(&self) -> Vec<String> {
+        self.login
+            .iter()
+            .flat_map(|l| l.uris.iter().flatten())
+            .filter_map(|u| u.uri.clone())
+            .collect()
+    }
+
+    /// The value of the first custom field with this name, ignoring case.
+    pub fn field(&self, name: &str) -> Option<String> {
+        self.fields
+            .iter()
+            .find(|f| matches!(&f.name, Some(n) if n.eq_ignore_ascii_case(name)))
+            .and_then(|f| f.value.clone())
+    }
+
+    pub fn ssh_public_key(&self) -> String {
+        self.ssh_key
+            .as_ref()
+            .and_then(|k| k.public_key.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn ssh_fingerprint(&self) -> String {
+        self.ssh_key
+            .as_ref()
+            .and_then(|k| k.key_fingerprint.clone())
+            .unwrap_or_default()
+    }
+
+    /// Bitwarden keeps SSH keys unencrypted, so the passphrase of the key file on disk (if
+    /// any) lives in a custom field.
+    pub fn ssh_passphrase(&self) -> String {
+        self.field(SSH_PASSPHRASE_FIELD).unwrap_or_default()
     }
 
     pub fn totp(&self) -> Result<String> {
-        Ok(call_bw(vec!["get", "totp", &self.id])?)
+        Ok(call_bw(&self.account, vec!["get", "totp", &self.id])?)
     }
 }
 
@@ -114,41 +509,691 @@ impl LoginItem {
 pub struct Login {
     pub username: Option<String>,
     pub password: Option<String>,
+    pub uris: Option<Vec<LoginUri>>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginUri {
+    pub uri: Option<String>,
 }
 
-#[derive(Clone, Serialize_repr, Deserialize_repr, PartialEq, Debug)]
-#[repr(u8)]
+#[derive(Clone, Copy, Deserialize, PartialEq, Debug)]
+#[serde(from = "u8")]
 pub enum ItemType {
-    Login = 1,
-    SecureNote = 2,
-    Card = 3,
-    Identity = 4,
+    Login,
+    SecureNote,
+    Card,
+    Identity,
+    SshKey,
+    /// Anything newer than this program, which is skipped instead of failing the whole list
+    Unknown(u8),
 }
 
-pub fn list_logins(info: ActiveWindowInfo) -> Result<Vec<LoginItem>> {
-    let stdout = call_bw(vec!["list", "items", "--url", &info.title])?;
-    let mut logins: Vec<LoginItem> = serde_json::from_str(&stdout)?;
+impl From<u8> for ItemType {
+    fn from(n: u8) -> Self {
+        match n {
+            1 => ItemType::Login,
+            2 => ItemType::SecureNote,
+            3 => ItemType::Card,
+            4 => ItemType::Identity,
+            5 => ItemType::SshKey,
+            n => ItemType::Unknown(n),
+        }
+    }
+}
 
-    let stdout = call_bw(vec!["list", "items", "--url", &info.executable])?;
-    let executable_logins: Vec<LoginItem> = serde_json::from_str(&stdout)?;
-    logins.extend(executable_logins);
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SshKey {
+    pub public_key: Option<String>,
+    pub key_fingerprint: Option<String>,
+    // The private key is left out on purpose, there's no reason to ever type it
+}
+
+/// Which windows a pattern is meant for, written like `title~=.*Cisco.*` (a regex that has to
+/// match the whole title) or `exe=vpnui.exe` (exactly this executable, ignoring case).
+#[derive(Debug)]
+struct WindowSelector {
+    executable: bool,
+    regex: regex::Regex,
+}
+
+impl WindowSelector {
+    fn matches(&self, window: &ActiveWindowInfo) -> bool {
+        if self.executable {
+            self.regex.is_match(&window.executable)
+        } else {
+            self.regex.is_match(&window.title)
+        }
+    }
+}
+
+impl FromStr for WindowSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("{:?} should look like title~=regex or exe=name", s))?;
+        let (key, regex) = match key.strip_suffix('~') {
+            Some(key) => (key, value.to_string()),
+            None => (key, regex::escape(value)),
+        };
+        let executable = match key.trim().to_lowercase().as_str() {
+            "title" => false,
+            "exe" | "executable" => true,
+            other => bail!("Unknown window property {:?}, use title or exe", other),
+        };
+        let case = if executable { "(?i)" } else { "" };
+        let regex = regex::Regex::new(&format!("{}^(?:{})$", case, regex))
+            .with_context(|| format!("Invalid regex in {:?}", s))?;
+        Ok(Self { executable, regex })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Field {
+    pub name: Option<String>,
+    pub value: Option<String>,
+}
+
+/// Finds the logins matching the window in all unlocked accounts, leaving out those the
+/// configured scope excludes.
+pub fn list_logins(info: &ActiveWindowInfo) -> Result<Vec<LoginItem>> {
+    let mut logins = vec![];
+    for account in unlocked_accounts() {
+        let names = names(&account)?;
+        let mut items = vec![];
+        for url in search_urls(info) {
+            let stdout = call_bw(&account, vec!["list", "items", "--url", url])?;
+            items.extend(serde_json::from_str::<Vec<LoginItem>>(&stdout)?);
+        }
+        items.extend(
+            ssh_keys(&account)?
+                .into_iter()
+                .filter(|key| key.selects(info)),
+        );
+
+        let config = CONFIG.read();
+        for mut item in items {
+            if let ItemType::Unknown(n) = item.item_type {
+                info!("Skipping {}, its type {} is unknown", item.name, n);
+                continue;
+            }
+            item.account = account.clone();
+            names.apply(&mut item);
+            if in_scope(&item, &config.scope) {
+                logins.push(item);
+            }
+        }
+    }
     Ok(logins)
 }
 
+/// What bw searches a window's logins by. Empty ones are left out, since bw would return
+/// everything for them.
+fn search_urls(info: &ActiveWindowInfo) -> Vec<&str> {
+    [info.title.as_str(), info.executable.as_str()]
+        .iter()
+        .copied()
+        .filter(|url| !url.is_empty())
+        .collect()
+}
+
+/// Folder, collection and organization names of an account, by id.
+#[derive(Clone, Default)]
+struct Names {
+    folders: HashMap<String, String>,
+    collections: HashMap<String, String>,
+    organizations: HashMap<String, String>,
+}
+
+impl Names {
+    fn apply(&self, item: &mut LoginItem) {
+        let lookup = |map: &HashMap<String, String>, id: &String| map.get(id).cloned();
+        item.folder = item
+            .folder_id
+            .as_ref()
+            .and_then(|id| lookup(&self.folders, id));
+        item.collections = item
+            .collection_ids
+            .iter()
+            .filter_map(|id| lookup(&self.collections, id))
+            .collect();
+        item.organization = item
+            .organization_id
+            .as_ref()
+            .and_then(|id| lookup(&self.organizations, id));
+    }
+}
+
+#[derive(Deserialize)]
+struct Named {
+    // null for the "No Folder" pseudo folder
+    id: Option<String>,
+    name: String,
+}
+
+// Looked up once per account and kept until the next sync
+fn names(account: &Account) -> Result<Names> {
+    if let Some(names) = NAMES.read().get(&account.name) {
+        return Ok(names.clone());
+    }
+    let list = |what: &str| -> Result<HashMap<String, String>> {
+        let stdout = call_bw(account, vec!["list", what])?;
+        let named: Vec<Named> = serde_json::from_str(&stdout)?;
+        Ok(named
+            .into_iter()
+            .filter_map(|n| Some((n.id?, n.name)))
+            .collect())
+    };
+    let names = Names {
+        folders: list("folders")?,
+        collections: list("collections")?,
+        organizations: list("organizations")?,
+    };
+    NAMES.write().insert(account.name.clone(), names.clone());
+    Ok(names)
+}
+
+// Looked up once per account and kept until the next sync
+fn ssh_keys(account: &Account) -> Result<Vec<LoginItem>> {
+    if let Some(keys) = SSH_KEYS.read().get(&account.name) {
+        return Ok(keys.clone());
+    }
+    let stdout = call_bw(account, vec!["list", "items"])?;
+    let items: Vec<LoginItem> = serde_json::from_str(&stdout)?;
+    let keys: Vec<LoginItem> = items
+        .into_iter()
+        .filter(|i| i.item_type == ItemType::SshKey)
+        .collect();
+    SSH_KEYS.write().insert(account.name.clone(), keys.clone());
+    Ok(keys)
+}
+
+fn in_scope(item: &LoginItem, scope: &Scope) -> bool {
+    let contains = |list: &[String], names: &[String]| {
+        list.iter()
+            .any(|l| names.iter().any(|n| n.to_lowercase() == l.to_lowercase()))
+    };
+    let allowed = |include: &[String], exclude: &[String], names: &[String]| {
+        (include.is_empty() || contains(include, names)) && !contains(exclude, names)
+    };
+    let folder: Vec<String> = item.folder.iter().cloned().collect();
+    let organization: Vec<String> = item.organization.iter().cloned().collect();
+    allowed(&scope.include_folders, &scope.exclude_folders, &folder)
+        && allowed(
+            &scope.include_collections,
+            &scope.exclude_collections,
+            &item.collections,
+        )
+        && allowed(
+            &scope.include_organizations,
+            &scope.exclude_organizations,
+            &organization,
+        )
+}
+
+/// A login to be created from the focused window.
+pub struct NewLogin {
+    pub name: String,
+    pub uris: Vec<String>,
+    /// Bitwarden's URI match type for all of `uris`, None for the account's default
+    pub match_type: Option<u8>,
+    pub username: String,
+    pub password: String,
+    pub pattern: Option<String>,
+}
+
+/// Creates a login item and syncs, so it's matched right away.
+pub fn create_login(account: &Account, login: NewLogin) -> Result<()> {
+    let template = call_bw(account, vec!["get", "template", "item"])?;
+    let mut item: serde_json::Value = serde_json::from_str(&template)?;
+    item["type"] = 1.into();
+    item["name"] = login.name.into();
+    item["notes"] = serde_json::Value::Null;
+    item["fields"] = match login.pattern {
+        Some(pattern) => serde_json::json!([pattern_field(&pattern)]),
+        None => serde_json::json!([]),
+    };
+    let match_type = login.match_type;
+    item["login"] = serde_json::json!({
+        "uris": login
+            .uris
+            .iter()
+            .map(|uri| serde_json::json!({ "match": match_type, "uri": uri }))
+            .collect::<Vec<_>>(),
+        "username": login.username,
+        "password": login.password,
+        "totp": null,
+    });
+
+    info!("Creating login {} in {}", item["name"], account.name);
+    let encoded = Zeroizing::new(base64::encode(item.to_string()));
+    call_bw_with(account, vec!["create", "item"], &[], Some(&encoded))?;
+    sync();
+    Ok(())
+}
+
+/// All login items of all unlocked accounts, for when the user searches the whole vault.
+pub fn list_all_logins() -> Result<Vec<LoginItem>> {
+    let mut logins = vec![];
+    for account in unlocked_accounts() {
+        let names = names(&account)?;
+        let stdout = call_bw(&account, vec!["list", "items"])?;
+        let items: Vec<LoginItem> = serde_json::from_str(&stdout)?;
+        for mut item in items {
+            if item.item_type == ItemType::Login {
+                item.account = account.clone();
+                names.apply(&mut item);
+                logins.push(item);
+            }
+        }
+    }
+    Ok(logins)
+}
+
+/// Suggests a regex for window titles like this one. Titles usually end with the program's
+/// name, while what comes before it (documents, pages, counters) changes.
+pub fn title_regex(title: &str) -> String {
+    let separators = [" - ", " \u{2014} ", " | "];
+    // Starts with the separator, e.g. " - Notepad"
+    let program = separators
+        .iter()
+        .filter_map(|s| title.rfind(s))
+        .max()
+        .map(|i| &title[i..]);
+    let escaped = match program {
+        Some(program) => format!(".*{}", regex::escape(program)),
+        None => regex::escape(title),
+    };
+    let digits = regex::Regex::new(r"\d+").unwrap();
+    format!("^{}$", digits.replace_all(&escaped, r"\d+"))
+}
+
+/// Adds a URI to the item and syncs, so the item matches right away.
+pub fn add_uri(item: &LoginItem, uri: &str, match_type: Option<u8>) -> Result<()> {
+    info!("Adding URI {} to {}", uri, item.name);
+    let mut json = get_item_json(&item.account, &item.id)?;
+    let uri = serde_json::json!({ "match": match_type, "uri": uri });
+    match json["login"]["uris"].as_array_mut() {
+        Some(uris) => uris.push(uri),
+        None => json["login"]["uris"] = serde_json::json!([uri]),
+    }
+    edit_item(&item.account, &item.id, &json)?;
+    sync();
+    Ok(())
+}
+
+/// Saves the item's pattern where it keeps it: in the pattern field, or in the notes for items
+/// that already have a pattern line there, leaving the rest of the notes alone. Items without
+/// a pattern get a pattern field. An empty pattern removes it. Syncs afterwards and returns the
+/// updated item.
+pub fn set_autotype_pattern(item: &LoginItem, pattern: &str) -> Result<LoginItem> {
+    info!("Setting the autotype pattern of {}", item.name);
+    let field_name = CONFIG.read().pattern_field.clone();
+    let mut json = get_item_json(&item.account, &item.id)?;
+    let mut updated = item.clone();
+
+    if item.field(&field_name).is_none() && item.has_pattern_line() {
+        let notes = replace_pattern_line(json["notes"].as_str().unwrap_or_default(), pattern);
+        json["notes"] = notes.clone().map_or(serde_json::Value::Null, |n| n.into());
+        updated.notes = notes;
+    } else {
+        if !json["fields"].is_array() {
+            json["fields"] = serde_json::json!([]);
+        }
+        let fields = json["fields"].as_array_mut().unwrap();
+        let is_pattern_field =
+            |name: Option<&str>| matches!(name, Some(n) if n.eq_ignore_ascii_case(&field_name));
+        fields.retain(|f| !is_pattern_field(f["name"].as_str()));
+        updated
+            .fields
+            .retain(|f| !is_pattern_field(f.name.as_deref()));
+        if !pattern.is_empty() {
+            fields.push(pattern_field(pattern));
+            updated.fields.push(Field {
+                name: Some(field_name.clone()),
+                value: Some(pattern.to_string()),
+            });
+        }
+    }
+
+    edit_item(&item.account, &item.id, &json)?;
+    sync();
+    Ok(updated)
+}
+
+fn pattern_field(pattern: &str) -> serde_json::Value {
+    // Type 0 is a plain text field
+    serde_json::json!({
+        "name": CONFIG.read().pattern_field,
+        "value": pattern,
+        "type": 0,
+        "linkedId": null,
+    })
+}
+
+// A pattern line without a selector, the one the picker edits
+fn is_plain_pattern_line(line: &str) -> bool {
+    matches!(PATTERN_LINE.captures(line), Some(c) if c.get(1).is_none())
+}
+
+// Replaces the first pattern line without a selector, or removes it if the pattern is empty
+fn replace_pattern_line(notes: &str, pattern: &str) -> Option<String> {
+    let mut replaced = String::new();
+    let mut found = false;
+    for line in notes.split_inclusive('\n') {
+        // Keep the line ending, be it \n, \r\n or none at all
+        let content = line.trim_end_matches(['\r', '\n']);
+        if found || !is_plain_pattern_line(content) {
+            replaced.push_str(line);
+            continue;
+        }
+        found = true;
+        if !pattern.is_empty() {
+            replaced.push_str(PATTERN_INDICATOR);
+            replaced.push_str(pattern);
+            replaced.push_str(&line[content.len()..]);
+        }
+    }
+    Some(replaced).filter(|n| !n.trim().is_empty())
+}
+
+// The raw item, so that editing it doesn't lose fields LoginItem leaves out
+fn get_item_json(account: &Account, id: &str) -> Result<serde_json::Value> {
+    let stdout = call_bw(account, vec!["get", "item", id])?;
+    Ok(serde_json::from_str(&stdout)?)
+}
+
+fn edit_item(account: &Account, id: &str, item: &serde_json::Value) -> Result<()> {
+    let encoded = Zeroizing::new(base64::encode(item.to_string()));
+    call_bw_with(account, vec!["edit", "item", id], &[], Some(&encoded))?;
+    Ok(())
+}
+
+/// Options of the generator that saving a new login offers, and the generator dialog starts with
+pub static STRONG_PASSWORD: &str = "length=20,uppercase,lowercase,number,special";
+
+/// Options for `bw generate`, written like `length=24,special` or `passphrase,words=5`.
+/// No options at all leaves everything to bw's defaults.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GeneratorOptions {
+    args: Vec<String>,
+}
+
+impl FromStr for GeneratorOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut args = vec![];
+        for option in s.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            let (key, value) = match option.split_once('=') {
+                Some((key, value)) => (key.trim(), Some(value.trim())),
+                None => (option, None),
+            };
+            let flag = match key.to_lowercase().as_str() {
+                "upper" | "uppercase" => "--uppercase",
+                "lower" | "lowercase" => "--lowercase",
+                "number" | "numbers" => "--number",
+                "special" => "--special",
+                "length" => "--length",
+                "passphrase" => "--passphrase",
+                "words" => "--words",
+                "separator" => "--separator",
+                "capitalize" => "--capitalize",
+                "includenumber" | "include_number" => "--includeNumber",
+                _ => bail!("Unknown generator option {:?}", key),
+            };
+            match (flag, value) {
+                ("--separator", Some(value)) => args.extend(vec![flag.into(), value.into()]),
+                ("--length", Some(value)) | ("--words", Some(value)) => {
+                    value
+                        .parse::<u32>()
+                        .with_context(|| format!("{} must be a number", key))?;
+                    args.extend(vec![flag.into(), value.into()]);
+                }
+                ("--length", None) | ("--words", None) | ("--separator", None) => {
+                    bail!("{} needs a value, like {}=5", key, key)
+                }
+                (_, None) => args.push(flag.into()),
+                (_, Some(_)) => bail!("{} doesn't take a value", key),
+            }
+        }
+        Ok(Self { args })
+    }
+}
+
+/// Generates a password or passphrase, following the same rules as `bw generate` does.
+pub fn generate(account: &Account, options: &GeneratorOptions) -> Result<String> {
+    let mut args = vec!["generate".to_string()];
+    args.extend(options.args.iter().cloned());
+    Ok(call_bw(account, args)?)
+}
+
+/// Sets a new password, keeping the old one in the item's password history like Bitwarden's
+/// own apps do. Syncs afterwards.
+pub fn update_password(item: &LoginItem, password: &str) -> Result<()> {
+    info!("Updating the password of {}", item.name);
+    let mut json = get_item_json(&item.account, &item.id)?;
+    let old = json["login"]["password"].take();
+    if old.is_string() && old != password {
+        let entry = serde_json::json!({
+            "lastUsedDate": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "password": old,
+        });
+        match json["passwordHistory"].as_array_mut() {
+            Some(history) => history.insert(0, entry),
+            None => json["passwordHistory"] = serde_json::json!([entry]),
+        }
+        if let Some(history) = json["passwordHistory"].as_array_mut() {
+            history.truncate(PASSWORD_HISTORY_LENGTH);
+        }
+    }
+    json["login"]["password"] = password.into();
+    edit_item(&item.account, &item.id, &json)?;
+    sync();
+    Ok(())
+}
+
+pub fn get_item(account: &Account, id: &str) -> Result<LoginItem> {
+    let stdout = call_bw(account, vec!["get", "item", id])?;
+    let mut item: LoginItem = serde_json::from_str(&stdout)?;
+    item.account = account.clone();
+    Ok(item)
+}
+
+/// Locks the account and forgets its session key, so it has to be unlocked again.
+pub fn lock(account: &Account) {
+    info!("Locking {}", account.name);
+    if let Err(e) = call_bw(account, vec!["lock"]) {
+        error!("Failed to lock vault: {:?}", e);
+    }
+    forget_session(account);
+}
+
+/// Locks all unlocked accounts.
+pub fn lock_all() {
+    unlocked_accounts().iter().for_each(lock);
+}
+
+/// Logs out of the account entirely, so the next login may use a different one.
+pub fn logout(account: &Account) {
+    info!("Logging out of {}", account.name);
+    if let Err(e) = call_bw(account, vec!["logout"]) {
+        error!("Failed to log out: {:?}", e);
+    }
+    forget_session(account);
+}
+
+fn forget_session(account: &Account) {
+    SESSIONS.write().remove(&account.name);
+    NAMES.write().remove(&account.name);
+    SSH_KEYS.write().remove(&account.name);
+    crate::focus::invalidate();
+    crate::tray::refresh();
+}
+
+fn start_session(account: &Account, key: String, password_hash: String) {
+    let now = Instant::now();
+    SESSIONS.write().insert(
+        account.name.clone(),
+        Session {
+            key: Zeroizing::new(key),
+            password_hash,
+            unlocked_at: now,
+            last_used: now,
+        },
+    );
+    // Logins resolved so far are missing the newly unlocked account's items
+    crate::focus::invalidate();
+}
+
+/// Pretends the account was unlocked, for tests that need a session to lock.
+#[cfg(all(test, target_os = "linux"))]
+pub fn insert_test_session(account: &Account) {
+    start_session(account, "test".into(), String::new());
+}
+
+pub fn is_unlocked(account: &Account) -> bool {
+    SESSIONS.read().contains_key(&account.name)
+}
+
+/// Checks the master password of an unlocked account against the one it was unlocked with.
+pub fn verify_master_password(account: &Account, password: &str) -> bool {
+    match SESSIONS.read().get(&account.name) {
+        Some(session) => password_matches(&session.password_hash, password),
+        None => false,
+    }
+}
+
+fn hash_master_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Failed to hash master password: {}", e))?
+        .to_string())
+}
+
+fn password_matches(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash)
+        .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+        .is_ok()
+}
+
+pub fn any_unlocked() -> bool {
+    !SESSIONS.read().is_empty()
+}
+
+pub fn unlocked_accounts() -> Vec<Account> {
+    let sessions = SESSIONS.read();
+    CONFIG
+        .read()
+        .accounts
+        .iter()
+        .filter(|a| sessions.contains_key(&a.name))
+        .cloned()
+        .collect()
+}
+
+/// Marks all sessions as in use, postponing the idle timeout.
+pub fn touch() {
+    let now = Instant::now();
+    SESSIONS
+        .write()
+        .values_mut()
+        .for_each(|s| s.last_used = now);
+}
+
+/// Returns the accounts that have been idle or unlocked for too long, and why.
+pub fn expired_sessions(
+    idle_limit: Option<Duration>,
+    lifetime: Option<Duration>,
+) -> Vec<(Account, String)> {
+    let sessions = SESSIONS.read();
+    CONFIG
+        .read()
+        .accounts
+        .iter()
+        .filter_map(|a| {
+            Some((
+                a.clone(),
+                sessions.get(&a.name)?.expiry(idle_limit, lifetime)?,
+            ))
+        })
+        .collect()
+}
+
+/// Syncs all unlocked accounts.
 pub fn sync() {
-    info!("Syncing");
-    if let Err(e) = call_bw(vec!["sync"]) {
-        error!("Failed to perform sync: {:?}", e);
+    // Folders and collections may have been renamed, SSH keys added
+    NAMES.write().clear();
+    SSH_KEYS.write().clear();
+    let mut errors = vec![];
+    for account in unlocked_accounts() {
+        info!("Syncing {}", account.name);
+        if let Err(e) = call_bw(&account, vec!["sync"]) {
+            error!("Failed to perform sync: {:?}", e);
+            errors.push(format!("Sync of {} failed: {}", account.name, e));
+        }
     }
+    crate::tray::update(Update::Error(if errors.is_empty() {
+        None
+    } else {
+        Some(errors.join(", "))
+    }));
+    crate::focus::invalidate();
+    crate::tray::refresh();
 }
 
 #[derive(Debug)]
 enum CliError {
     InvalidPassword,
+    /// The account needs a second factor, or a new device verification code
+    TwoStepRequired {
+        new_device: bool,
+    },
+    InvalidCode,
     Status(std::process::ExitStatus),
     FailedToRun(std::io::Error),
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum TwoStepMessage {
+    CodeRequired,
+    NewDevice,
+    InvalidCode,
+}
+
+// Instead of prompting for a second factor, non-interactive bw fails with these, matched
+// case-insensitively against its stderr
+static TWO_STEP_MESSAGES: &[(&str, TwoStepMessage)] = &[
+    ("Code is required", TwoStepMessage::CodeRequired),
+    ("No provider selected", TwoStepMessage::CodeRequired),
+    ("new device", TwoStepMessage::NewDevice),
+    ("token is invalid", TwoStepMessage::InvalidCode),
+    ("Invalid code", TwoStepMessage::InvalidCode),
+];
+
+fn two_step_error(stderr: &str) -> Option<CliError> {
+    let stderr = stderr.to_lowercase();
+    let says = |message| {
+        TWO_STEP_MESSAGES
+            .iter()
+            .any(|(text, m)| *m == message && stderr.contains(&text.to_lowercase()))
+    };
+    if says(TwoStepMessage::CodeRequired) {
+        Some(CliError::TwoStepRequired {
+            new_device: says(TwoStepMessage::NewDevice),
+        })
+    } else if says(TwoStepMessage::InvalidCode) {
+        Some(CliError::InvalidCode)
+    } else {
+        None
+    }
+}
+
 impl Display for CliError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self)
@@ -157,19 +1202,51 @@ impl Display for CliError {
 
 impl std::error::Error for CliError {}
 
-fn call_bw<A>(args: Vec<A>) -> std::result::Result<String, CliError>
+fn call_bw<A>(account: &Account, args: Vec<A>) -> std::result::Result<String, CliError>
 where
     A: Into<OsString> + AsRef<OsStr>,
 {
-    let output = Command::new("bw")
-        .args(&args)
-        .env(
-            "BW_SESSION",
-            SESSION_KEY.read().as_ref().unwrap_or(&"".to_string()), // Passing an empty string will make bitwarden ignore it
-        )
-        .creation_flags(0x08000000) //CREATE_NO_WINDOW
-        .output()
-        .map_err(CliError::FailedToRun)?;
+    call_bw_with(account, args, &[], None)
+}
+
+/// Calls bw with extra environment variables, and writes `input` to its stdin. Secrets should
+/// be passed this way rather than as arguments, which other processes can read.
+fn call_bw_with<A>(
+    account: &Account,
+    args: Vec<A>,
+    env: &[(&str, &str)],
+    input: Option<&str>,
+) -> std::result::Result<String, CliError>
+where
+    A: Into<OsString> + AsRef<OsStr>,
+{
+    let mut command = Command::new(&CONFIG.read().bw_path);
+    command.args(&args).envs(env.iter().copied()).env(
+        "BW_SESSION",
+        SESSIONS
+            .read()
+            .get(&account.name)
+            .map_or("", |s| s.key.as_str()), // Passing an empty string will make bitwarden ignore it
+    );
+    #[cfg(windows)]
+    command.creation_flags(0x08000000); //CREATE_NO_WINDOW
+    if let Some(data_dir) = &account.data_dir {
+        command.env("BITWARDENCLI_APPDATA_DIR", data_dir);
+    }
+    let output = match input {
+        None => command.output(),
+        Some(input) => command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                // Dropping stdin closes it, so bw knows the input is complete
+                child.stdin.take().unwrap().write_all(input.as_bytes())?;
+                child.wait_with_output()
+            }),
+    }
+    .map_err(CliError::FailedToRun)?;
     let stdout = String::from_utf8_lossy(&output.stdout);
     let stderr = String::from_utf8_lossy(&output.stderr);
 
@@ -177,8 +1254,11 @@ where
         if stderr.contains("Invalid master password") {
             return Err(CliError::InvalidPassword);
         }
+        if let Some(e) = two_step_error(&stderr) {
+            return Err(e);
+        }
 
-        let subcmd: OsString = args.get(0).unwrap().into();
+        let subcmd: OsString = args.first().unwrap().into();
         let status_code = output
             .status
             .code()
@@ -196,3 +1276,427 @@ where
 
     Ok(stdout.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_two_step_stderr() {
+        // Without --method, then with each method's code missing
+        for stderr in &[
+            "No provider selected.",
+            "Code is required.",
+            "? Two-step login code: Code is required.",
+        ] {
+            assert!(
+                matches!(
+                    two_step_error(stderr),
+                    Some(CliError::TwoStepRequired { new_device: false })
+                ),
+                "{:?}",
+                stderr
+            );
+        }
+        assert!(matches!(
+            two_step_error("New device verification required. Code is required."),
+            Some(CliError::TwoStepRequired { new_device: true })
+        ));
+        for stderr in &["Two-step token is invalid. Try again.", "Invalid code."] {
+            assert!(
+                matches!(two_step_error(stderr), Some(CliError::InvalidCode)),
+                "{:?}",
+                stderr
+            );
+        }
+        for stderr in &["Invalid master password.", "You are not logged in.", ""] {
+            assert!(two_step_error(stderr).is_none(), "{:?}", stderr);
+        }
+    }
+
+    #[test]
+    fn verifies_master_password_hashes() {
+        let hash = hash_master_password("correct horse").unwrap();
+        assert!(!hash.contains("correct horse"));
+        assert!(password_matches(&hash, "correct horse"));
+        assert!(!password_matches(&hash, "correct horse "));
+        assert!(!password_matches(&hash, ""));
+        // Salted, so the same password never hashes the same way twice
+        assert_ne!(hash, hash_master_password("correct horse").unwrap());
+        assert!(!password_matches("not a hash", "correct horse"));
+    }
+
+    #[test]
+    fn suggests_title_regexes() {
+        assert_eq!(title_regex("notes.txt - Notepad"), r"^.* \- Notepad$");
+        assert_eq!(title_regex("Inbox (12) | Mail"), r"^.* \| Mail$");
+        assert_eq!(title_regex("PuTTY"), "^PuTTY$");
+        assert_eq!(title_regex("Server 42 console"), r"^Server \d+ console$");
+
+        let regex = regex::Regex::new(&title_regex("Inbox (12) - Mail - Firefox")).unwrap();
+        assert!(regex.is_match("Settings - Firefox"));
+        assert!(!regex.is_match("Settings - Chrome"));
+        let regex = regex::Regex::new(&title_regex("Session 3 [1.2.3.4]")).unwrap();
+        assert!(regex.is_match("Session 14 [10.0.0.1]"));
+        assert!(!regex.is_match("Session x [1.2.3.4]"));
+    }
+
+    #[test]
+    fn parses_generator_options() {
+        let args = |s: &str| s.parse::<GeneratorOptions>().unwrap().args;
+        assert!(args("").is_empty());
+        assert!(args(" , ").is_empty());
+        assert_eq!(
+            args(STRONG_PASSWORD),
+            vec![
+                "--length",
+                "20",
+                "--uppercase",
+                "--lowercase",
+                "--number",
+                "--special"
+            ]
+        );
+        assert_eq!(
+            args("Passphrase, words = 5, separator=_, capitalize, include_number"),
+            vec![
+                "--passphrase",
+                "--words",
+                "5",
+                "--separator",
+                "_",
+                "--capitalize",
+                "--includeNumber"
+            ]
+        );
+        assert_eq!(
+            args("upper,lower,numbers"),
+            vec!["--uppercase", "--lowercase", "--number"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_generator_options() {
+        for invalid in &[
+            "colour",
+            "length",
+            "length=",
+            "length=long",
+            "length=-5",
+            "words",
+            "separator",
+            "special=yes",
+            "passphrase=1",
+        ] {
+            assert!(
+                invalid.parse::<GeneratorOptions>().is_err(),
+                "{:?} was accepted",
+                invalid
+            );
+        }
+    }
+
+    fn item(json: serde_json::Value) -> LoginItem {
+        let mut item = serde_json::json!({ "id": "1", "type": 1, "name": "Example" });
+        item.as_object_mut()
+            .unwrap()
+            .extend(json.as_object().unwrap().clone());
+        serde_json::from_value(item).unwrap()
+    }
+
+    #[test]
+    fn reads_patterns_from_fields_and_notes() {
+        let from_notes = item(serde_json::json!({
+            "notes": "Some notes\r\n  autotype : {PASSWORD}{ENTER}\nmore notes",
+        }));
+        assert_eq!(
+            from_notes.autotype_pattern(None).as_deref(),
+            Some("{PASSWORD}{ENTER}")
+        );
+        assert!(from_notes.has_pattern_line());
+
+        // The field wins over the notes, and its name's case doesn't matter
+        let both = item(serde_json::json!({
+            "notes": "Autotype: {PASSWORD}",
+            "fields": [{ "name": "AutoType", "value": "{USERNAME}{ENTER}" }],
+        }));
+        assert_eq!(
+            both.autotype_pattern(None).as_deref(),
+            Some("{USERNAME}{ENTER}")
+        );
+
+        let empty = item(serde_json::json!({
+            "notes": "Autotype:   \nno pattern here",
+            "fields": [{ "name": "autotype", "value": " " }, { "name": "other", "value": "x" }],
+        }));
+        assert_eq!(empty.autotype_pattern(None), None);
+        assert_eq!(empty.effective_pattern(None), CONFIG.read().default_pattern);
+    }
+
+    #[test]
+    fn replaces_the_plain_pattern_line() {
+        let notes = "first\r\nAutotype[exe=vpn.exe]: {PASSWORD}\r\nAutotype: {PASSWORD}\r\nlast";
+        assert_eq!(
+            replace_pattern_line(notes, "{USERNAME}").as_deref(),
+            Some("first\r\nAutotype[exe=vpn.exe]: {PASSWORD}\r\nAutotype: {USERNAME}\r\nlast")
+        );
+        assert_eq!(
+            replace_pattern_line(notes, "").as_deref(),
+            Some("first\r\nAutotype[exe=vpn.exe]: {PASSWORD}\r\nlast")
+        );
+        // Only the first one, without a trailing line break
+        assert_eq!(
+            replace_pattern_line("autotype: a\nAutotype: b", "c").as_deref(),
+            Some("Autotype: c\nAutotype: b")
+        );
+        assert_eq!(replace_pattern_line("Autotype: {PASSWORD}\n", ""), None);
+        assert!(!is_plain_pattern_line("Autotype[title=x]: {PASSWORD}"));
+        assert!(!is_plain_pattern_line("Not an autotype: line"));
+    }
+
+    fn window(title: &str, executable: &str) -> ActiveWindowInfo {
+        ActiveWindowInfo {
+            title: title.into(),
+            executable: executable.into(),
+        }
+    }
+
+    #[test]
+    fn unlocking_drops_pre_resolved_logins() {
+        let _candidates = crate::focus::tests::CANDIDATES_LOCK.lock();
+        let window = window("Example - Browser", "browser.exe");
+        crate::focus::tests::resolve(&window);
+        assert!(crate::focus::cached_logins(&window).is_some());
+
+        let account = Account {
+            name: "unlocking_drops_pre_resolved_logins".into(),
+            ..Account::default()
+        };
+        start_session(&account, "key".into(), String::new());
+        assert!(crate::focus::cached_logins(&window).is_none());
+        SESSIONS.write().remove(&account.name);
+    }
+
+    #[test]
+    fn configures_the_server_and_its_services() {
+        assert_eq!(
+            server_args(&Credentials::default()),
+            vec!["config", "server", DEFAULT_SERVER]
+        );
+        let credentials = Credentials {
+            server_url: " https://vault.example.com ".into(),
+            api_url: "https://api.example.com".into(),
+            notifications_url: " ".into(),
+            ..Credentials::default()
+        };
+        assert_eq!(
+            server_args(&credentials),
+            vec![
+                "config",
+                "server",
+                "https://vault.example.com",
+                "--api",
+                "https://api.example.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn never_searches_for_an_empty_url() {
+        assert_eq!(
+            search_urls(&window("GitHub - Browser", "browser.exe")),
+            vec!["GitHub - Browser", "browser.exe"]
+        );
+        // The executable is empty when its process couldn't be queried
+        assert_eq!(
+            search_urls(&window("GitHub - Browser", "")),
+            vec!["GitHub - Browser"]
+        );
+        assert!(search_urls(&window("", "")).is_empty());
+    }
+
+    #[test]
+    fn window_selectors_match_whole_properties() {
+        let selector = |s: &str| s.parse::<WindowSelector>().unwrap();
+        let vpn = window("Cisco AnyConnect | vpn.example.com", "vpnui.exe");
+
+        assert!(selector("title~=Cisco.*").matches(&vpn));
+        assert!(!selector("title~=AnyConnect").matches(&vpn));
+        assert!(!selector("title~=cisco.*").matches(&vpn));
+        assert!(selector("title=Cisco AnyConnect | vpn.example.com").matches(&vpn));
+        assert!(!selector("title=Cisco").matches(&vpn));
+        // Executables ignore case, and only regexes are regexes
+        assert!(selector("exe=VPNUI.EXE").matches(&vpn));
+        assert!(selector("executable~=vpn.*").matches(&vpn));
+        assert!(!selector("exe=vpn.*").matches(&vpn));
+        assert!(!selector("exe=vpnui").matches(&vpn));
+
+        for invalid in &["Cisco", "class=vpn", "title~=(unclosed", "=x"] {
+            assert!(
+                invalid.parse::<WindowSelector>().is_err(),
+                "{:?} was accepted",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn picks_the_pattern_for_the_window() {
+        let item = item(serde_json::json!({
+            "notes": "Autotype[exe=vpnui.exe]: {PASSWORD}{ENTER}\nAutotype[nonsense]: {TAB}",
+            "fields": [
+                { "name": "autotype[title~=.*Login.*]", "value": "{USERNAME}{ENTER}" },
+                { "name": "autotype", "value": "{USERNAME}{TAB}{PASSWORD}" },
+            ],
+        }));
+        let pattern = |w: Option<&ActiveWindowInfo>| item.autotype_pattern(w).unwrap();
+
+        assert_eq!(pattern(None), "{USERNAME}{TAB}{PASSWORD}");
+        assert_eq!(
+            pattern(Some(&window("Browser", "firefox.exe"))),
+            "{USERNAME}{TAB}{PASSWORD}"
+        );
+        assert_eq!(
+            pattern(Some(&window("VPN", "VpnUI.exe"))),
+            "{PASSWORD}{ENTER}"
+        );
+        // Fields come first
+        assert_eq!(
+            pattern(Some(&window("Login", "vpnui.exe"))),
+            "{USERNAME}{ENTER}"
+        );
+    }
+
+    #[test]
+    fn scopes_items_by_folder_collection_and_organization() {
+        let mut personal = item(serde_json::json!({}));
+        personal.folder = Some("Work".into());
+        let mut shared = item(serde_json::json!({}));
+        shared.collections = vec!["Test accounts".into(), "Servers".into()];
+        shared.organization = Some("Example Inc".into());
+        let scope = |toml: &str| -> Scope { toml::from_str(toml).unwrap() };
+
+        let everything = scope("");
+        assert!(in_scope(&personal, &everything) && in_scope(&shared, &everything));
+
+        let no_tests = scope("exclude_collections = ['test ACCOUNTS']");
+        assert!(in_scope(&personal, &no_tests));
+        assert!(!in_scope(&shared, &no_tests));
+
+        // Items without an organization aren't in any
+        let organization_only = scope("include_organizations = ['Example Inc']");
+        assert!(!in_scope(&personal, &organization_only));
+        assert!(in_scope(&shared, &organization_only));
+
+        // Excludes win over includes
+        let conflicting = scope("include_folders = ['Work']\nexclude_folders = ['work']");
+        assert!(!in_scope(&personal, &conflicting));
+        assert!(!in_scope(&shared, &scope("include_folders = ['Work']")));
+    }
+
+    #[test]
+    fn keeps_unknown_item_types_apart() {
+        let items: Vec<LoginItem> = serde_json::from_str(
+            r#"[
+                { "id": "1", "type": 1, "name": "Login" },
+                { "id": "5", "type": 5, "name": "Key",
+                  "sshKey": { "publicKey": "ssh-ed25519 AAAA", "keyFingerprint": "SHA256:abc" },
+                  "fields": [{ "name": "Passphrase", "value": "secret" }] },
+                { "id": "9", "type": 9, "name": "From the future" }
+            ]"#,
+        )
+        .unwrap();
+        let types: Vec<ItemType> = items.iter().map(|i| i.item_type).collect();
+        assert_eq!(
+            types,
+            vec![ItemType::Login, ItemType::SshKey, ItemType::Unknown(9)]
+        );
+
+        let key = &items[1];
+        assert_eq!(key.ssh_public_key(), "ssh-ed25519 AAAA");
+        assert_eq!(key.ssh_fingerprint(), "SHA256:abc");
+        assert_eq!(key.ssh_passphrase(), "secret");
+        assert_eq!(
+            key.effective_pattern(None),
+            CONFIG.read().default_ssh_pattern
+        );
+        assert_eq!(items[0].ssh_passphrase(), "");
+    }
+
+    #[test]
+    fn ssh_keys_match_the_windows_their_patterns_select() {
+        let key = item(serde_json::json!({
+            "type": 5,
+            "fields": [
+                { "name": "autotype", "value": "{SSH_PUBLIC_KEY}" },
+                { "name": "autotype[exe=putty.exe]", "value": "{SSH_PASSPHRASE}{ENTER}" },
+            ],
+            "notes": "Autotype[title~=.*@example\\.com.*]: {SSH_FINGERPRINT}",
+        }));
+        let putty = window("PuTTY Configuration", "putty.exe");
+        let terminal = window("me@example.com: ~", "terminal.exe");
+        assert!(key.selects(&putty));
+        assert!(key.selects(&terminal));
+        assert!(!key.selects(&window("Example - Browser", "browser.exe")));
+        assert_eq!(
+            key.effective_pattern(Some(&putty)),
+            "{SSH_PASSPHRASE}{ENTER}"
+        );
+        assert_eq!(key.effective_pattern(Some(&terminal)), "{SSH_FINGERPRINT}");
+
+        // A pattern without a selector is meant for any window the item matches, not all
+        let unselected = item(serde_json::json!({
+            "type": 5,
+            "fields": [{ "name": "autotype", "value": "{SSH_PASSPHRASE}" }],
+        }));
+        assert!(!unselected.selects(&putty));
+    }
+
+    #[test]
+    fn expires_idle_and_old_sessions() {
+        let minutes = |m: u64| Duration::from_secs(60 * m);
+        // Kept short, an Instant can't go back further than the system has been up
+        let session = |unlocked_ago, used_ago| {
+            let now = Instant::now();
+            Session {
+                key: Zeroizing::new("key".into()),
+                password_hash: String::new(),
+                unlocked_at: now - minutes(unlocked_ago),
+                last_used: now - minutes(used_ago),
+            }
+        };
+        let fresh = session(2, 1);
+        let idle = session(4, 3);
+        let old = session(9, 1);
+
+        for s in &[&fresh, &idle, &old] {
+            assert_eq!(s.expiry(None, None), None);
+        }
+        assert_eq!(fresh.expiry(Some(minutes(2)), Some(minutes(5))), None);
+        assert_eq!(
+            idle.expiry(Some(minutes(2)), Some(minutes(5))).as_deref(),
+            Some("idle for 2 min")
+        );
+        assert_eq!(idle.expiry(None, Some(minutes(5))), None);
+        assert_eq!(
+            old.expiry(Some(minutes(2)), Some(minutes(5))).as_deref(),
+            Some("unlocked for 5 min")
+        );
+        assert_eq!(old.expiry(Some(minutes(2)), None), None);
+    }
+
+    #[test]
+    fn compares_servers_loosely() {
+        assert!(same_server(None, "https://vault.bitwarden.com"));
+        assert!(same_server(None, " https://Vault.Bitwarden.com/ "));
+        assert!(same_server(
+            Some("https://vault.example.com/"),
+            "https://vault.example.com"
+        ));
+        assert!(!same_server(None, "https://vault.example.com"));
+        assert!(!same_server(
+            Some("https://vault.bitwarden.eu"),
+            "https://vault.bitwarden.com"
+        ));
+    }
+}
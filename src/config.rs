@@ -0,0 +1,333 @@
+use crate::hotkeys::{Action, Hotkey};
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use serde::de::IntoDeserializer;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+static CONFIG_FILE: &str = "config.toml";
+
+lazy_static! {
+    pub static ref CONFIG: RwLock<Config> = RwLock::new(Config::default());
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(deserialize_with = "deserialize_hotkeys")]
+    pub hotkeys: BTreeMap<Action, Hotkey>,
+    /// The pattern used for logins that don't specify their own
+    pub default_pattern: String,
+    /// The pattern used for SSH keys that don't specify their own
+    pub default_ssh_pattern: String,
+    /// Name of the custom field holding an item's own pattern
+    pub pattern_field: String,
+    /// Pause between two simulated keystrokes
+    pub key_delay_millis: u64,
+    pub sync_interval_minutes: u64,
+    /// Lock the vault after this long without any hotkey being used
+    pub idle_lock_minutes: Option<u64>,
+    /// Lock the vault this long after it was unlocked, no matter what
+    pub session_lifetime_minutes: Option<u64>,
+    /// Lock the vault when the desktop session is locked or the system goes to sleep
+    pub lock_on_system_lock: bool,
+    /// Name or path of the Bitwarden CLI executable
+    pub bw_path: PathBuf,
+    pub log_file: PathBuf,
+    /// Show the number of matching logins for the focused window in the tray tooltip
+    pub match_indicator: bool,
+    /// How many recently autotyped logins to offer in the tray menu
+    pub recent_items: usize,
+    /// Offer to save a new login when nothing matches the focused window
+    pub offer_new_login: bool,
+    pub accounts: Vec<Account>,
+    pub scope: Scope,
+}
+
+/// Limits which items are matched against windows, by folder, collection and organization
+/// name. Empty include lists include everything.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scope {
+    pub include_folders: Vec<String>,
+    pub exclude_folders: Vec<String>,
+    pub include_collections: Vec<String>,
+    pub exclude_collections: Vec<String>,
+    pub include_organizations: Vec<String>,
+    pub exclude_organizations: Vec<String>,
+}
+
+/// A Bitwarden account. Each one needs its own data directory, since bw only keeps a single
+/// login (and server) per directory.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Account {
+    /// Shown in the tray, the login dialog and the picker
+    pub name: String,
+    /// Prefilled in the login dialog until bw knows better
+    pub email: Option<String>,
+    /// Passed to bw as `BITWARDENCLI_APPDATA_DIR`, bw's own default if unset
+    pub data_dir: Option<PathBuf>,
+    /// Preselected in the login dialog
+    #[serde(default)]
+    pub login_method: LoginMethod,
+    /// Prefilled in the login dialog for the API key method. The secret is never stored.
+    pub client_id: Option<String>,
+    /// Prefilled in the login dialog and checked before unlocking, bitwarden.com if unset
+    pub server_url: Option<String>,
+    /// Only needed if the server's services don't live under `server_url`, prefilled in the
+    /// login dialog
+    pub identity_url: Option<String>,
+    pub api_url: Option<String>,
+    pub notifications_url: Option<String>,
+}
+
+/// How an account logs in. Afterwards, the vault is always unlocked with the master password.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum LoginMethod {
+    #[default]
+    Password,
+    /// `bw login --apikey`, with the personal API key's client id and secret
+    ApiKey,
+    /// `bw login --sso`, which finishes in the browser
+    Sso,
+}
+
+impl LoginMethod {
+    pub const ALL: [LoginMethod; 3] =
+        [LoginMethod::Password, LoginMethod::ApiKey, LoginMethod::Sso];
+}
+
+impl Display for LoginMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LoginMethod::Password => "Email and master password",
+            LoginMethod::ApiKey => "API key",
+            LoginMethod::Sso => "Single sign-on (SSO)",
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            hotkeys: vec![(Action::Autotype, "Ctrl+Alt+A".parse().unwrap())]
+                .into_iter()
+                .collect(),
+            default_pattern: "{USERNAME}{TAB}{PASSWORD}{ENTER}".into(),
+            default_ssh_pattern: "{SSH_PASSPHRASE}{ENTER}".into(),
+            pattern_field: "autotype".into(),
+            key_delay_millis: 20,
+            sync_interval_minutes: 5,
+            idle_lock_minutes: None,
+            session_lifetime_minutes: None,
+            lock_on_system_lock: true,
+            bw_path: "bw".into(),
+            log_file: "bitwarden-autotype.log".into(),
+            match_indicator: false,
+            recent_items: 5,
+            offer_new_login: true,
+            accounts: vec![Account {
+                name: "Bitwarden".into(),
+                ..Account::default()
+            }],
+            scope: Scope::default(),
+        }
+    }
+}
+
+// toml can't deserialize table keys into enums by itself
+fn deserialize_hotkeys<'de, D>(deserializer: D) -> Result<BTreeMap<Action, Hotkey>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    BTreeMap::<String, Hotkey>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(action, hotkey)| Ok((Action::deserialize(action.into_deserializer())?, hotkey)))
+        .collect()
+}
+
+impl Config {
+    fn validate(&self) -> Result<()> {
+        for (action, hotkey) in &self.hotkeys {
+            if let Some((other, _)) = self
+                .hotkeys
+                .iter()
+                .find(|(a, h)| a < &action && h == &hotkey)
+            {
+                bail!("{} is bound to both {:?} and {:?}", hotkey, other, action);
+            }
+        }
+        if self.default_pattern.trim().is_empty() {
+            bail!("default_pattern must not be empty");
+        }
+        if self.default_ssh_pattern.trim().is_empty() {
+            bail!("default_ssh_pattern must not be empty");
+        }
+        if self.pattern_field.trim().is_empty() {
+            bail!("pattern_field must not be empty");
+        }
+        if self.sync_interval_minutes == 0 {
+            bail!("sync_interval_minutes must be at least 1");
+        }
+        if self.idle_lock_minutes == Some(0) {
+            bail!("idle_lock_minutes must be at least 1, leave it out to never lock");
+        }
+        if self.session_lifetime_minutes == Some(0) {
+            bail!("session_lifetime_minutes must be at least 1, leave it out to never lock");
+        }
+        if self.bw_path.as_os_str().is_empty() {
+            bail!("bw_path must not be empty");
+        }
+        if self.accounts.is_empty() {
+            bail!("at least one account is required");
+        }
+        for (i, account) in self.accounts.iter().enumerate() {
+            if account.name.trim().is_empty() {
+                bail!("every account needs a name");
+            }
+            let urls = [
+                &account.server_url,
+                &account.identity_url,
+                &account.api_url,
+                &account.notifications_url,
+            ];
+            if urls
+                .iter()
+                .any(|u| matches!(u, Some(u) if u.trim().is_empty()))
+            {
+                bail!(
+                    "account {:?} has an empty URL, leave it out instead",
+                    account.name
+                );
+            }
+            if self.accounts[..i].iter().any(|a| a.name == account.name) {
+                bail!("there is more than one account named {:?}", account.name);
+            }
+            if self.accounts.len() > 1 {
+                match &account.data_dir {
+                    None => bail!("account {:?} needs a data_dir", account.name),
+                    Some(dir)
+                        if self.accounts[..i]
+                            .iter()
+                            .any(|a| a.data_dir.as_ref() == Some(dir)) =>
+                    {
+                        bail!("accounts must not share the data_dir {}", dir.display())
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the config file lives, e.g. `%APPDATA%\bitwarden-autotype\config.toml`
+/// or `$XDG_CONFIG_HOME/bitwarden-autotype/config.toml`.
+pub fn path() -> Option<PathBuf> {
+    dirs::config_dir().map(|d| d.join(env!("CARGO_PKG_NAME")).join(CONFIG_FILE))
+}
+
+/// Loads and validates the config file, falling back to the defaults if there is none.
+pub fn load() -> Result<()> {
+    let path = match path() {
+        Some(p) if p.exists() => p,
+        _ => return Ok(()),
+    };
+
+    let content = std::fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let config: Config =
+        toml::from_str(&content).with_context(|| format!("Failed to parse {}", path.display()))?;
+    config
+        .validate()
+        .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+
+    *CONFIG.write() = config;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> Result<Config> {
+        let config: Config = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn readme_examples() -> Vec<&'static str> {
+        include_str!("../README.md")
+            .split("```toml\n")
+            .skip(1)
+            .map(|block| block.split("```").next().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn readme_examples_parse() {
+        let examples = readme_examples();
+        assert!(examples.len() >= 5);
+        for example in examples {
+            if let Err(e) = parse(example) {
+                panic!("{:?} in\n{}", e, example);
+            }
+        }
+    }
+
+    #[test]
+    fn readme_shows_the_defaults() {
+        let documented = parse(readme_examples()[0]).unwrap();
+        assert_eq!(
+            format!("{:?}", documented),
+            format!("{:?}", Config::default())
+        );
+    }
+
+    #[test]
+    fn hotkeys_bind_actions() {
+        let config =
+            parse("[hotkeys]\nautotype = 'Ctrl+Alt+A'\nrepeat_last = 'Ctrl+Alt+R'").unwrap();
+        assert_eq!(config.hotkeys.len(), 2);
+        assert_eq!(config.hotkeys[&Action::Autotype].to_string(), "Ctrl+Alt+A");
+        assert_eq!(
+            config.hotkeys[&Action::RepeatLast].to_string(),
+            "Ctrl+Alt+R"
+        );
+    }
+
+    #[test]
+    fn accounts_default_to_the_password_method() {
+        let config = parse("[[accounts]]\nname = 'Work'").unwrap();
+        assert_eq!(config.accounts[0].login_method, LoginMethod::Password);
+        let config = parse("[[accounts]]\nname = 'Work'\nlogin_method = 'sso'").unwrap();
+        assert_eq!(config.accounts[0].login_method, LoginMethod::Sso);
+    }
+
+    #[test]
+    fn rejects_invalid_configs() {
+        for invalid in &[
+            "unknown_key = 1",
+            "sync_interval_minutes = 0",
+            "idle_lock_minutes = 0",
+            "default_pattern = ' '",
+            "[hotkeys]\nautotype = 'Ctrl+Alt+A'\npicker = 'ctrl+alt+a'",
+            "[hotkeys]\nautotype = 'Ctrl+Alt'",
+            "[hotkeys]\nunknown_action = 'Ctrl+Alt+A'",
+            "accounts = []",
+            "[[accounts]]\nname = ''",
+            "[[accounts]]\nname = 'A'\nserver_url = ''",
+            "[[accounts]]\nname = 'A'\nlogin_method = 'magic'",
+            "[[accounts]]\nname = 'A'\ndata_dir = 'a'\n[[accounts]]\nname = 'A'\ndata_dir = 'b'",
+            "[[accounts]]\nname = 'A'\n[[accounts]]\nname = 'B'\ndata_dir = 'b'",
+            "[[accounts]]\nname = 'A'\ndata_dir = 'a'\n[[accounts]]\nname = 'B'\ndata_dir = 'a'",
+        ] {
+            assert!(parse(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+    }
+}
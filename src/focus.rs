@@ -0,0 +1,169 @@
+use crate::bw_cli::LoginItem;
+use crate::tray::Update;
+use crate::window::{self, WindowHandle};
+use crate::ActiveWindowInfo;
+use lazy_static::lazy_static;
+use log::{debug, error};
+use parking_lot::{Mutex, RwLock};
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+// The last window that could have been autotyped into, kept so that clicking the tray
+// (which focuses the taskbar) doesn't make us forget where the user was
+static LAST_TARGET_WINDOW: AtomicIsize = AtomicIsize::new(0);
+// Bumped by every invalidation, so logins resolved from an older vault are thrown away
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref CANDIDATES: RwLock<Option<Candidates>> = RwLock::new(None);
+    static ref FOCUS_SENDER: Mutex<Option<Sender<WindowHandle>>> = Mutex::new(None);
+}
+
+/// The logins matching a window, resolved before the user asked for them.
+struct Candidates {
+    window: ActiveWindowInfo,
+    logins: Vec<LoginItem>,
+}
+
+/// Returns the pre-resolved logins if they were computed for this exact window.
+pub fn cached_logins(window: &ActiveWindowInfo) -> Option<Vec<LoginItem>> {
+    CANDIDATES
+        .read()
+        .as_ref()
+        .filter(|c| &c.window == window)
+        .map(|c| c.logins.clone())
+}
+
+/// The most recently focused window that doesn't belong to us or the taskbar.
+pub fn last_target_window() -> WindowHandle {
+    LAST_TARGET_WINDOW.load(Ordering::SeqCst)
+}
+
+/// Drops the pre-resolved logins, e.g. because the vault changed after a sync.
+pub fn invalidate() {
+    let mut candidates = CANDIDATES.write();
+    GENERATION.fetch_add(1, Ordering::SeqCst);
+    *candidates = None;
+}
+
+/// Stores resolved logins unless the candidates were invalidated since `generation`.
+fn store(generation: usize, resolved: Candidates) -> bool {
+    let mut candidates = CANDIDATES.write();
+    if GENERATION.load(Ordering::SeqCst) != generation {
+        return false;
+    }
+    *candidates = Some(resolved);
+    true
+}
+
+/// Hooks into foreground window changes and keeps the candidate list up to date.
+/// Blocks forever, so this should be run in its own thread.
+pub fn watch(show_indicator: bool) {
+    let (s, r) = channel();
+    *FOCUS_SENDER.lock() = Some(s);
+    std::thread::spawn(move || resolve_candidates(r, show_indicator));
+    window::watch_foreground(on_foreground_change);
+}
+
+fn on_foreground_change(window_handle: WindowHandle) {
+    if !window::is_own(window_handle) && !window::is_taskbar(window_handle) {
+        LAST_TARGET_WINDOW.store(window_handle, Ordering::SeqCst);
+    }
+    if let Some(s) = FOCUS_SENDER.lock().as_ref() {
+        let _ = s.send(window_handle);
+    }
+}
+
+fn resolve_candidates(r: Receiver<WindowHandle>, show_indicator: bool) {
+    while let Ok(mut window_handle) = r.recv() {
+        // Calling bw is slow, so skip any windows the user has already tabbed past
+        while let Ok(newer) = r.try_recv() {
+            window_handle = newer;
+        }
+
+        if window::is_own(window_handle)
+            || window::is_taskbar(window_handle)
+            || !crate::bw_cli::any_unlocked()
+        {
+            continue;
+        }
+
+        // Untitled windows would match every login in the vault
+        let window = ActiveWindowInfo::from_handle(window_handle);
+        if window.title.is_empty() {
+            continue;
+        }
+        if CANDIDATES.read().as_ref().map(|c| &c.window) == Some(&window) {
+            continue;
+        }
+
+        let generation = GENERATION.load(Ordering::SeqCst);
+        match crate::bw_cli::list_logins(&window) {
+            Ok(logins) => {
+                let count = logins.len();
+                let title = window.title.clone();
+                if !store(generation, Candidates { window, logins }) {
+                    debug!(
+                        "Dropped logins for {} resolved before an invalidation",
+                        title
+                    );
+                    continue;
+                }
+                debug!("Pre-resolved {} logins for {}", count, title);
+                if show_indicator {
+                    crate::tray::update(Update::Matches(count));
+                }
+            }
+            Err(e) => {
+                error!("Failed to pre-resolve logins: {:?}", e);
+                if show_indicator {
+                    crate::tray::update(Update::Matches(0));
+                }
+                invalidate();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Held by tests that need the candidates to themselves, since unlocking and locking
+    /// in other tests invalidates them.
+    pub static CANDIDATES_LOCK: Mutex<()> = parking_lot::const_mutex(());
+
+    /// Pretends no logins match `window`.
+    pub fn resolve(window: &ActiveWindowInfo) {
+        store(
+            GENERATION.load(Ordering::SeqCst),
+            Candidates {
+                window: window.clone(),
+                logins: vec![],
+            },
+        );
+    }
+
+    #[test]
+    fn drops_logins_resolved_before_an_invalidation() {
+        let _candidates = CANDIDATES_LOCK.lock();
+        let window = ActiveWindowInfo {
+            title: "Example - Browser".into(),
+            executable: "browser.exe".into(),
+        };
+        let resolved = || Candidates {
+            window: window.clone(),
+            logins: vec![],
+        };
+
+        let generation = GENERATION.load(Ordering::SeqCst);
+        invalidate();
+        assert!(!store(generation, resolved()));
+        assert!(cached_logins(&window).is_none());
+
+        assert!(store(GENERATION.load(Ordering::SeqCst), resolved()));
+        assert_eq!(cached_logins(&window).map(|l| l.len()), Some(0));
+        invalidate();
+        assert!(cached_logins(&window).is_none());
+    }
+}
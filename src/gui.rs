@@ -1,95 +1,767 @@
 use anyhow::Result;
 
-use crate::bw_cli::LoginItem;
+use crate::bw_cli::{LoginItem, NewLogin, TwoStepMethod};
+use crate::config::LoginMethod;
 use fltk::app::{set_focus, App};
-use fltk::button::Button;
-use fltk::enums::{Key, Shortcut};
+use fltk::button::{Button, CheckButton};
+use fltk::dialog;
+use fltk::enums::{CallbackTrigger, Event, EventState, Key, Shortcut};
 use fltk::image::PngImage;
 use fltk::input::{Input, SecretInput};
+use fltk::menu::Choice;
 use fltk::prelude::*;
 use fltk::table::{Table, TableContext};
 use fltk::window::Window;
 use fltk::{draw, enums};
 use lazy_static::lazy_static;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 
 lazy_static! {
     static ref ICON: PngImage = PngImage::from_data(include_bytes!("../assets/icon.png")).unwrap();
 }
 
-pub fn prompt_bw_login(bitwarden_email: Option<String>) -> Result<(String, String)> {
+pub fn alert(message: &str) {
+    let _app = App::default();
+    dialog::alert_default(message);
+    // Required so we can spawn windows in separate threads (like the hotkey thread).
+    fltk::app::unlock();
+}
+
+/// Asks which second factor to log in with. Returns `None` if the user closed the dialog.
+pub fn choose_two_step_method(account_name: &str) -> Option<TwoStepMethod> {
+    // Not a choice dialog, since those pick their first button on Escape
+    let app = App::default();
+    let mut window = Window::new(100, 100, 420, 120, None);
+    window.set_label(&format!("{} requires two-step login", account_name));
+    window.set_icon(Some(ICON.clone()));
+
+    let mut method = Choice::new(110, 20, 290, 30, "Method");
+    let mut submit = Button::new(300, 70, 100, 30, "Continue");
+    for m in &TwoStepMethod::ALL {
+        method.add_choice(&m.to_string());
+    }
+    method.set_value(0);
+
+    window.end();
+    window.show();
+    if !run_modal(app, &mut window, &mut submit, || true) {
+        return None;
+    }
+    TwoStepMethod::ALL
+        .get(method.value().max(0) as usize)
+        .copied()
+}
+
+/// What to do with a password that was just generated and typed.
+pub enum SaveGenerated {
+    NewLogin,
+    UpdateItem,
+}
+
+/// Asks whether to save a generated password. Returns `None` if it shouldn't be saved.
+pub fn save_generated_choice(item_name: &str) -> Option<SaveGenerated> {
+    let _app = App::default();
+    let choice = dialog::choice2_default(
+        "Do you want to save the generated password?",
+        "Don't save",
+        "Save as new login",
+        &format!("Update {}", item_name),
+    );
+    match choice {
+        Some(1) => Some(SaveGenerated::NewLogin),
+        Some(2) => Some(SaveGenerated::UpdateItem),
+        _ => None,
+    }
+}
+
+/// Asks how to generate a password, prefilled with `options`. Returns `None` if the user
+/// cancelled.
+pub fn prompt_generator_options(options: &str) -> Option<String> {
+    let _app = App::default();
+    dialog::input_default(
+        "Generator options, separated by commas:\n\
+         uppercase, lowercase, number, special, length=N for passwords, or\n\
+         passphrase, words=N, separator=X, capitalize, includenumber for passphrases",
+        options,
+    )
+}
+
+/// Asks for the master password before using a protected item. Returns `None` if the user
+/// cancelled.
+pub fn prompt_master_password(message: &str) -> Option<String> {
+    let _app = App::default();
+    dialog::password_default(message, "")
+}
+
+/// Asks for a two-step login code. Returns `None` if the user cancelled.
+pub fn prompt_code(message: &str) -> Option<String> {
+    let _app = App::default();
+    dialog::input_default(message, "")
+}
+
+/// What the user entered into the login dialog.
+#[derive(Default)]
+pub struct Credentials {
+    pub method: LoginMethod,
+    pub email: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub password: String,
+    /// Empty for the official bitwarden.com server
+    pub server_url: String,
+    /// Only needed if the server's services don't live under `server_url`, empty otherwise
+    pub identity_url: String,
+    pub api_url: String,
+    pub notifications_url: String,
+}
+
+/// Asks for whatever the login method needs, the master password and the server, prefilled
+/// from `defaults`. While logged in only unlocking is possible, so the method and server can't
+/// be changed. Returns `None` if the user closed the dialog.
+pub fn prompt_bw_login(
+    account_name: &str,
+    defaults: Credentials,
+    logged_in: bool,
+) -> Result<Option<Credentials>> {
     let app = App::default();
-    let mut window = Window::new(100, 100, 400, 120, "Log into Bitwarden");
+    let mut window = Window::new(100, 100, 420, 270, None);
+    window.set_label(&format!("Log into {}", account_name));
     window.set_icon(Some(ICON.clone()));
 
-    let mut email = Input::new(80, 20, 300, 30, "E-Mail");
-    let password = SecretInput::new(80, 70, 300, 30, "Password");
+    let mut method = Choice::new(110, 20, 290, 30, "Log in with");
+    let mut email = Input::new(110, 70, 290, 30, "E-Mail");
+    let mut client_id = Input::new(110, 70, 290, 30, "Client ID");
+    let client_secret = SecretInput::new(110, 120, 290, 30, "Client secret");
+    let mut password = SecretInput::new(110, 170, 290, 30, "Password");
+    let mut server = Input::new(110, 220, 290, 30, "Server");
+    let mut services = CheckButton::new(110, 270, 290, 30, "Separate service URLs");
+    let mut identity = Input::new(110, 320, 290, 30, "Identity");
+    let mut api = Input::new(110, 370, 290, 30, "API");
+    let mut notifications = Input::new(110, 420, 290, 30, "Notifications");
     let mut submit = Button::new(0, 0, 0, 0, "");
 
+    for m in &LoginMethod::ALL {
+        method.add_choice(&m.to_string());
+    }
+    method.set_value(
+        LoginMethod::ALL
+            .iter()
+            .position(|m| m == &defaults.method)
+            .unwrap() as i32,
+    );
+    email.set_value(&defaults.email);
+    client_id.set_value(&defaults.client_id);
+    client_id.set_tooltip("From \"View API key\" in the web vault's security settings");
+    password.set_tooltip("Your master password, which unlocks the vault after logging in");
+    server.set_tooltip(
+        "Leave empty for bitwarden.com, e.g. https://vault.bitwarden.eu for the EU cloud",
+    );
+    server.set_value(&defaults.server_url);
+    identity.set_value(&defaults.identity_url);
+    api.set_value(&defaults.api_url);
+    notifications.set_value(&defaults.notifications_url);
+    services.set_checked(
+        [
+            &defaults.identity_url,
+            &defaults.api_url,
+            &defaults.notifications_url,
+        ]
+        .iter()
+        .any(|url| !url.is_empty()),
+    );
+    if logged_in {
+        server.deactivate();
+        services.deactivate();
+        identity.deactivate();
+        api.deactivate();
+        notifications.deactivate();
+    }
+
     window.end();
     window.show();
 
-    if let Some(e) = bitwarden_email {
-        email.set_value(&e);
+    // Only shows the inputs the chosen method needs, stacked below each other. The service
+    // URLs are rarely needed, so they stay collapsed below the server until asked for.
+    let layout = {
+        let (mut window, mut method, mut email, mut client_id, mut client_secret, mut password) = (
+            window.clone(),
+            method.clone(),
+            email.clone(),
+            client_id.clone(),
+            client_secret.clone(),
+            password.clone(),
+        );
+        let (mut server, mut services, mut identity, mut api, mut notifications) = (
+            server.clone(),
+            services.clone(),
+            identity.clone(),
+            api.clone(),
+            notifications.clone(),
+        );
+        move || {
+            let chosen = match logged_in {
+                true => LoginMethod::Password,
+                false => LoginMethod::ALL[method.value() as usize],
+            };
+            let mut y = 20;
+            place(&mut method, !logged_in, &mut y);
+            place(&mut email, chosen == LoginMethod::Password, &mut y);
+            place(&mut client_id, chosen == LoginMethod::ApiKey, &mut y);
+            place(&mut client_secret, chosen == LoginMethod::ApiKey, &mut y);
+            place(&mut password, true, &mut y);
+            place(&mut server, true, &mut y);
+            place(&mut services, true, &mut y);
+            let expanded = services.is_checked();
+            place(&mut identity, expanded, &mut y);
+            place(&mut api, expanded, &mut y);
+            place(&mut notifications, expanded, &mut y);
+            window.resize(window.x(), window.y(), window.w(), y);
+            window.redraw();
+        }
+    };
+    let layout = Rc::new(RefCell::new(layout));
+    layout.borrow_mut()();
+    let method_layout = layout.clone();
+    method.set_callback(move |_| method_layout.borrow_mut()());
+    services.set_callback(move |_| layout.borrow_mut()());
+
+    if !defaults.email.is_empty() || logged_in {
         set_focus(&password);
     }
+    if !run_modal(app, &mut window, &mut submit, || true) {
+        return Ok(None);
+    }
+    Ok(Some(Credentials {
+        method: if logged_in {
+            LoginMethod::Password
+        } else {
+            LoginMethod::ALL[method.value() as usize]
+        },
+        email: email.value(),
+        client_id: client_id.value(),
+        client_secret: client_secret.value(),
+        password: password.value(),
+        server_url: server.value(),
+        identity_url: identity.value(),
+        api_url: api.value(),
+        notifications_url: notifications.value(),
+    }))
+}
+
+/// Shows the dialog until it's submitted, by `submit` or Enter, or closed. `accept` may keep
+/// it open, e.g. because something entered is invalid. Returns whether it was submitted.
+fn run_modal(
+    app: App,
+    window: &mut Window,
+    submit: &mut Button,
+    accept: impl Fn() -> bool + 'static,
+) -> bool {
+    let submitted = Rc::new(Cell::new(false));
+    let submit_submitted = submitted.clone();
     submit.set_shortcut(Shortcut::from_key(Key::Enter));
-    submit.set_callback(move |_| app.quit());
+    submit.set_callback(move |_| {
+        if accept() {
+            submit_submitted.set(true);
+            app.quit()
+        }
+    });
+    // Escape and the close button both end up here
+    window.set_callback(move |w| {
+        w.hide();
+        app.quit()
+    });
 
     app.run().unwrap();
     // Required so we can spawn windows in separate threads (like the hotkey thread).
     // As of now, no two GUIs can run concurrently, so this will not cause issues.
     fltk::app::unlock();
-    Ok((email.value(), password.value()))
+    submitted.get()
 }
 
-pub fn login_choice(items: Vec<LoginItem>) -> Result<LoginItem> {
+fn place<W: WidgetExt>(widget: &mut W, visible: bool, y: &mut i32) {
+    if visible {
+        widget.resize(widget.x(), *y, widget.w(), widget.h());
+        widget.show();
+        *y += 50;
+    } else {
+        widget.hide();
+    }
+}
+
+/// Lets the user pick one of the suggested URIs for an item, and edit it. Each suggestion is a
+/// label, the URI and its match type. Returns `None` if the user cancelled.
+pub fn uri_dialog(
+    item_name: &str,
+    suggestions: &[(&str, String, Option<u8>)],
+) -> Result<Option<(String, Option<u8>)>> {
     let app = App::default();
-    let mut window = Window::new(100, 100, 800, 150, "Choose a Login");
+    let mut window = Window::new(100, 100, 560, 170, None);
+    window.set_label(&format!("Add a URI to {}", item_name));
     window.set_icon(Some(ICON.clone()));
 
-    let mut table = Table::new(20, 10, window.width() - 40, window.height() - 20, "");
-    table.set_rows(items.len() as i32);
-    table.set_cols(3);
-    table.set_col_width_all(table.width() / (table.cols() + 1) - 1);
-    table.set_col_width(2, table.col_width(2) * 2); // Give the autotype patterns space
-    table.set_selection(0, 0, 0, 0);
+    let mut suggestion = Choice::new(100, 20, 440, 30, "Match by");
+    let mut uri = Input::new(100, 70, 440, 30, "URI");
+    let mut submit = Button::new(440, 120, 100, 30, "Add");
+
+    for (label, _, _) in suggestions {
+        suggestion.add_choice(label);
+    }
+    suggestion.set_value(0);
+    uri.set_value(&suggestions[0].1);
+
+    window.end();
+    window.show();
+    set_focus(&uri);
+
+    let suggested_uris: Vec<String> = suggestions.iter().map(|s| s.1.clone()).collect();
+    let mut choice_uri = uri.clone();
+    suggestion.set_callback(move |c| {
+        if let Some(suggested) = suggested_uris.get(c.value().max(0) as usize) {
+            choice_uri.set_value(suggested);
+        }
+    });
+
+    if !run_modal(app, &mut window, &mut submit, || true) || uri.value().trim().is_empty() {
+        return Ok(None);
+    }
+    let match_type = suggestions[suggestion.value().max(0) as usize].2;
+    Ok(Some((uri.value(), match_type)))
+}
+
+// Bitwarden's URI match types offered when saving a new login
+static MATCH_TYPES: [(&str, Option<u8>); 4] = [
+    ("Exact", Some(3)),
+    ("Starts with", Some(2)),
+    ("Regular expression", Some(4)),
+    ("Account default", None),
+];
+
+/// Asks for the details of a new login for the focused window, prefilled with its title and
+/// executable. `generate` makes up a password for the chosen account.
+/// Returns the index of the chosen account and the login, or `None` if the user cancelled.
+pub fn new_login_dialog(
+    account_names: &[String],
+    title: &str,
+    executable: &str,
+    password_value: &str,
+    generate: impl Fn(usize) -> Result<String> + 'static,
+    validate_pattern: impl Fn(&str) -> Result<()> + 'static,
+) -> Result<Option<(usize, NewLogin)>> {
+    let app = App::default();
+    // The account only needs choosing if there is more than one
+    let top = if account_names.len() > 1 { 70 } else { 20 };
+    let mut window = Window::new(100, 100, 460, top + 420, "Save new login for this window");
+    window.set_icon(Some(ICON.clone()));
+
+    let mut account = Choice::new(120, 20, 320, 30, "Account");
+    let mut name = Input::new(120, top, 320, 30, "Name");
+    let mut title_uri = Input::new(120, top + 50, 320, 30, "Window title");
+    let mut executable_uri = Input::new(120, top + 100, 320, 30, "Executable");
+    let mut match_type = Choice::new(120, top + 150, 320, 30, "Match");
+    let username = Input::new(120, top + 200, 320, 30, "Username");
+    let mut password = SecretInput::new(120, top + 250, 230, 30, "Password");
+    let mut generate_button = Button::new(360, top + 250, 80, 30, "Generate");
+    let mut pattern = Input::new(120, top + 300, 320, 30, "Pattern");
+    let mut submit = Button::new(340, top + 360, 100, 30, "Save");
+
+    for account_name in account_names {
+        account.add_choice(account_name);
+    }
+    account.set_value(0);
+    if account_names.len() <= 1 {
+        account.hide();
+    }
+    name.set_value(title);
+    title_uri.set_value(title);
+    executable_uri.set_value(executable);
+    title_uri.set_tooltip("Leave empty to not match the window title");
+    executable_uri.set_tooltip("Leave empty to not match the executable");
+    for (label, _) in &MATCH_TYPES {
+        match_type.add_choice(label);
+    }
+    match_type.set_value(0);
+    password.set_value(password_value);
+    pattern.set_tooltip("Optional, the default pattern is used if empty");
+
+    window.end();
+    window.show();
+    set_focus(&username);
+
+    let (generate_account, mut generate_password) = (account.clone(), password.clone());
+    generate_button.set_callback(move |_| {
+        match generate(generate_account.value().max(0) as usize) {
+            Ok(generated) => generate_password.set_value(&generated),
+            Err(e) => dialog::alert_default(&format!("Failed to generate a password: {:#}", e)),
+        }
+    });
+
+    let submit_pattern = pattern.clone();
+    let submitted = run_modal(app, &mut window, &mut submit, move || {
+        // Keep the dialog open, so nothing that was entered is lost
+        match validate_pattern(&submit_pattern.value()) {
+            Ok(()) => true,
+            Err(e) => {
+                dialog::alert_default(&format!("Invalid pattern: {:#}", e));
+                false
+            }
+        }
+    });
+    if !submitted {
+        return Ok(None);
+    }
+
+    let pattern = pattern.value();
+    let login = NewLogin {
+        name: name.value(),
+        uris: vec![title_uri.value(), executable_uri.value()]
+            .into_iter()
+            .filter(|u| !u.trim().is_empty())
+            .collect(),
+        match_type: MATCH_TYPES[match_type.value().max(0) as usize].1,
+        username: username.value(),
+        password: password.value(),
+        pattern: Some(pattern).filter(|p| !p.trim().is_empty()),
+    };
+    Ok(Some((account.value().max(0) as usize, login)))
+}
+
+static ROW_HEIGHT: i32 = 25;
+static MAX_INITIAL_ROWS: usize = 10;
+static COLUMNS: [&str; 6] = [
+    "Name",
+    "Username",
+    "URI",
+    "Folder",
+    "Account",
+    "Autotype Pattern",
+];
+
+/// Lets the user pick one of the items. Returns `None` if the user closed the picker.
+/// With `edit_pattern`, the selected item's pattern can be edited too; it saves the pattern
+/// and returns the updated item.
+pub fn login_choice(
+    title: &str,
+    items: Vec<LoginItem>,
+    edit_pattern: Option<fn(&LoginItem, &str) -> Result<LoginItem>>,
+) -> Result<Option<LoginItem>> {
+    let app = App::default();
+    let table_height = ROW_HEIGHT * (items.len().min(MAX_INITIAL_ROWS) as i32 + 1) + 2;
+    let mut window = Window::new(100, 100, 800, table_height + 60, None);
+    window.set_label(title);
+    window.set_icon(Some(ICON.clone()));
+
+    let filter_width = match edit_pattern {
+        Some(_) => window.width() - 220,
+        None => window.width() - 100,
+    };
+    let mut filter = Input::new(80, 10, filter_width, 30, "Search");
+    let mut edit = Button::new(window.width() - 130, 10, 110, 30, "Edit Pattern");
+    edit.set_tooltip("Ctrl+E");
+    if edit_pattern.is_none() {
+        edit.hide();
+    }
+
+    let mut table = Table::new(20, 50, window.width() - 40, table_height, "");
+    table.set_cols(COLUMNS.len() as i32);
+    table.set_col_header(true);
+    table.set_row_header(true);
+    table.set_row_header_width(30);
+    table.set_row_height_all(ROW_HEIGHT);
+    table.set_col_header_height(ROW_HEIGHT);
     table.end();
 
     let mut submit = Button::new(0, 0, 0, 0, "");
 
+    window.resizable(&table);
+    window.size_range(400, 150, 0, 0);
     window.end();
     window.show();
 
-    let closure_items = items.clone();
-    table.draw_cell(move |t, ctx, row, col, x, y, w, h| {
-        if ctx == TableContext::Cell {
-            let item: &LoginItem = closure_items.get(row as usize).unwrap();
-            let data = match col {
-                0 => item.name.clone(),
-                1 => item
-                    .login
-                    .clone()
-                    .map(|l| l.username)
-                    .flatten()
-                    .unwrap_or_else(|| "".into()),
-                2 => item
-                    .autotype_pattern()
-                    .unwrap_or_else(|| crate::DEFAULT_PATTERN.to_string()),
-                _ => "".into(),
+    table.set_rows(items.len() as i32);
+    // Indices into `rows`, in the order they are currently shown
+    let visible = Rc::new(RefCell::new((0..items.len()).collect::<Vec<usize>>()));
+    let selected = Rc::new(Cell::new(0));
+    // Shared, since editing a pattern replaces the item and its row
+    let rows: Vec<[String; 6]> = items.iter().map(row_data).collect();
+    let rows = Rc::new(RefCell::new(rows));
+    let items = Rc::new(RefCell::new(items));
+
+    fit_columns(&mut table, &rows.borrow(), &visible.borrow());
+
+    let (draw_rows, draw_visible, draw_selected) =
+        (rows.clone(), visible.clone(), selected.clone());
+    table.draw_cell(move |_t, ctx, row, col, x, y, w, h| match ctx {
+        TableContext::ColHeader => draw_header(COLUMNS[col as usize], x, y, w, h),
+        // Rows 1 to 9 can be chosen with their number key
+        TableContext::RowHeader if row < 9 => draw_header(&(row + 1).to_string(), x, y, w, h),
+        TableContext::Cell => {
+            if let Some(i) = draw_visible.borrow().get(row as usize) {
+                let selected = draw_selected.get() == row;
+                draw_data(&draw_rows.borrow()[*i][col as usize], x, y, w, h, selected)
+            }
+        }
+        _ => {}
+    });
+
+    let (click_selected, mut click_submit) = (selected.clone(), submit.clone());
+    table.set_callback(move |t| {
+        if t.callback_context() == TableContext::Cell {
+            click_selected.set(t.callback_row());
+            t.redraw();
+            if fltk::app::event_clicks() {
+                click_submit.do_callback();
+            }
+        }
+    });
+
+    let (filter_rows, filter_visible, filter_selected, mut filter_table) = (
+        rows.clone(),
+        visible.clone(),
+        selected.clone(),
+        table.clone(),
+    );
+    filter.set_trigger(CallbackTrigger::Changed);
+    filter.set_callback(move |f| {
+        let mut visible = filter_visible.borrow_mut();
+        let rows = filter_rows.borrow();
+        *visible = filter_rows_by(&rows, &f.value());
+        filter_selected.set(0);
+        filter_table.set_rows(visible.len() as i32);
+        filter_table.set_row_position(0);
+        fit_columns(&mut filter_table, &rows, &visible);
+        filter_table.redraw();
+    });
+
+    let (edit_rows, edit_items, edit_visible, edit_selected, mut edit_table, edit_filter) = (
+        rows.clone(),
+        items.clone(),
+        visible.clone(),
+        selected.clone(),
+        table.clone(),
+        filter.clone(),
+    );
+    edit.set_callback(move |_| {
+        let (save, i) = match (
+            edit_pattern,
+            edit_visible.borrow().get(edit_selected.get() as usize),
+        ) {
+            (Some(save), Some(i)) => (save, *i),
+            _ => return,
+        };
+        let item = edit_items.borrow()[i].clone();
+        let current = item.effective_pattern(None);
+        let mut value = current.clone();
+        loop {
+            let message = format!(
+                "Autotype pattern for {}, leave it empty to use the default:",
+                item.name
+            );
+            value = match dialog::input_default(&message, &value) {
+                Some(v) if v == current => break,
+                Some(v) if v.trim().is_empty() => String::new(),
+                Some(v) => v,
+                None => break,
             };
-            draw_data(&data, x, y, w, h, t.is_selected(row, col))
+            match save(&item, &value) {
+                Ok(updated) => {
+                    edit_rows.borrow_mut()[i] = row_data(&updated);
+                    edit_items.borrow_mut()[i] = updated;
+                    fit_columns(&mut edit_table, &edit_rows.borrow(), &edit_visible.borrow());
+                    edit_table.redraw();
+                    break;
+                }
+                Err(e) => dialog::alert_default(&format!("{:#}", e)),
+            }
         }
+        set_focus(&edit_filter);
     });
 
-    submit.set_shortcut(Shortcut::from_key(Key::Enter));
-    submit.set_callback(move |_| app.quit());
+    let (key_visible, key_selected, mut key_table, mut key_submit, mut key_edit) = (
+        visible.clone(),
+        selected.clone(),
+        table.clone(),
+        submit.clone(),
+        edit.clone(),
+    );
+    filter.handle(move |f, ev| {
+        if ev != Event::KeyDown {
+            return false;
+        }
+        if edit_pattern.is_some()
+            && fltk::app::event_state().contains(EventState::Ctrl)
+            && fltk::app::event_key() == Key::from_char('e')
+        {
+            key_edit.do_callback();
+            return true;
+        }
+        let count = key_visible.borrow().len() as i32;
+        let page = ((key_table.height() - ROW_HEIGHT) / ROW_HEIGHT).max(1);
+        let current = key_selected.get();
+        let target = match fltk::app::event_key() {
+            Key::Up => current - 1,
+            Key::Down => current + 1,
+            Key::PageUp => current - page,
+            Key::PageDown => current + page,
+            _ => match quick_select_number(f.value().is_empty()) {
+                Some(n) if n <= count => {
+                    key_selected.set(n - 1);
+                    key_submit.do_callback();
+                    return true;
+                }
+                _ => return false,
+            },
+        };
+        if count > 0 {
+            let target = target.max(0).min(count - 1);
+            key_selected.set(target);
+            if target < key_table.row_position() || target >= key_table.row_position() + page {
+                key_table.set_row_position((target - page + 1).max(0).min(target));
+            }
+            key_table.redraw();
+        }
+        true
+    });
 
-    app.run().unwrap();
-    let (i, _, _, _) = table.get_selection();
-    // Required so we can spawn windows in separate threads (like the hotkey thread).
-    // As of now, no two GUIs can run concurrently, so this will not cause issues.
-    fltk::app::unlock();
-    Ok(items.get(i as usize).unwrap().clone())
+    let (resize_rows, resize_visible, mut resize_table) =
+        (rows.clone(), visible.clone(), table.clone());
+    window.resize_callback(move |_, _, _, _, _| {
+        fit_columns(
+            &mut resize_table,
+            &resize_rows.borrow(),
+            &resize_visible.borrow(),
+        );
+    });
+
+    set_focus(&filter);
+
+    let submit_visible = visible.clone();
+    // Nothing to choose if the search matches nothing
+    let submitted = run_modal(app, &mut window, &mut submit, move || {
+        !submit_visible.borrow().is_empty()
+    });
+    if !submitted {
+        return Ok(None);
+    }
+    let chosen = visible.borrow().get(selected.get() as usize).copied();
+    let items = items.borrow();
+    Ok(chosen.map(|i| items[i].clone()))
+}
+
+fn row_data(item: &LoginItem) -> [String; 6] {
+    [
+        item.name.clone(),
+        item.username(),
+        item.uris().join(", "),
+        item.folder.clone().unwrap_or_default(),
+        item.account.name.clone(),
+        item.effective_pattern(None),
+    ]
+}
+
+// Digits select a row directly while the search is empty; once something has been typed,
+// they are part of the search and Alt has to be held.
+fn quick_select_number(search_is_empty: bool) -> Option<i32> {
+    if !search_is_empty && !fltk::app::event_state().contains(EventState::Alt) {
+        return None;
+    }
+    match fltk::app::event_text().chars().next() {
+        Some(c @ '1'..='9') => c.to_digit(10).map(|n| n as i32),
+        _ => None,
+    }
+}
+
+fn filter_rows_by(rows: &[[String; 6]], search: &str) -> Vec<usize> {
+    let search = search.trim();
+    if search.is_empty() {
+        return (0..rows.len()).collect();
+    }
+
+    // The autotype pattern is not worth searching, so only the first five columns count
+    let mut scored: Vec<(i32, usize)> = rows
+        .iter()
+        .enumerate()
+        .filter_map(|(i, row)| {
+            row[..5]
+                .iter()
+                .filter_map(|field| fuzzy_score(search, field))
+                .max()
+                .map(|score| (score, i))
+        })
+        .collect();
+    // Best matches first, ties keep the order bitwarden returned them in
+    scored.sort_by_key(|(score, i)| (-score, *i));
+    scored.into_iter().map(|(_, i)| i).collect()
+}
+
+/// Scores how well `text` contains the characters of `search` in order, ignoring case.
+/// Consecutive characters and characters at the start of words score higher, gaps lower.
+fn fuzzy_score(search: &str, text: &str) -> Option<i32> {
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let mut score = 0;
+    let mut pos = 0;
+    let mut last_match: Option<usize> = None;
+
+    for c in search.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let found = pos + text[pos..].iter().position(|t| *t == c)?;
+        score += 1;
+        if found == 0 || !text[found - 1].is_alphanumeric() {
+            score += 5;
+        }
+        match last_match {
+            Some(last) if last + 1 == found => score += 3,
+            Some(last) => score -= (found - last - 1).min(5) as i32,
+            None => score -= found.min(5) as i32,
+        }
+        last_match = Some(found);
+        pos = found + 1;
+    }
+    Some(score)
+}
+
+// Gives every column the width of its widest entry, and spreads any leftover space evenly
+fn fit_columns(table: &mut Table, rows: &[[String; 6]], visible: &[usize]) {
+    let padding = 20;
+    draw::set_font(enums::Font::Helvetica, 14);
+    let mut widths: Vec<i32> = COLUMNS
+        .iter()
+        .enumerate()
+        .map(|(col, header)| {
+            visible
+                .iter()
+                .map(|i| draw::measure(&rows[*i][col], false).0)
+                .chain(std::iter::once(draw::measure(header, false).0))
+                .max()
+                .unwrap_or(0)
+                + padding
+        })
+        .collect();
+
+    // Leave room for the row header and a vertical scrollbar
+    let available = table.width() - table.row_header_width() - 20;
+    let total: i32 = widths.iter().sum();
+    if total < available {
+        let extra = (available - total) / widths.len() as i32;
+        widths.iter_mut().for_each(|w| *w += extra);
+    }
+    for (col, width) in widths.iter().enumerate() {
+        table.set_col_width(col as i32, *width);
+    }
+}
+
+fn draw_header(txt: &str, x: i32, y: i32, w: i32, h: i32) {
+    draw::push_clip(x, y, w, h);
+    draw::draw_box(
+        enums::FrameType::ThinUpBox,
+        x,
+        y,
+        w,
+        h,
+        enums::Color::FrameDefault,
+    );
+    draw::set_draw_color(enums::Color::Black);
+    draw::draw_text2(txt, x, y, w, h, enums::Align::Center);
+    draw::pop_clip();
 }
 
 // The selected flag sets the color of the cell to a grayish color, otherwise white
@@ -106,3 +778,51 @@ fn draw_data(txt: &str, x: i32, y: i32, w: i32, h: i32, selected: bool) {
     draw::draw_rect(x, y, w, h);
     draw::pop_clip();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(name: &str, username: &str, uri: &str) -> [String; 6] {
+        [
+            name.into(),
+            username.into(),
+            uri.into(),
+            String::new(),
+            "Bitwarden".into(),
+            "{USERNAME}{TAB}{PASSWORD}{ENTER}".into(),
+        ]
+    }
+
+    #[test]
+    fn fuzzy_score_needs_every_character_in_order() {
+        assert!(fuzzy_score("gh", "GitHub").is_some());
+        assert!(fuzzy_score("G H", "github").is_some());
+        assert!(fuzzy_score("hg", "GitHub").is_none());
+        assert!(fuzzy_score("gitlab", "GitHub").is_none());
+        assert_eq!(fuzzy_score("", "GitHub"), Some(0));
+    }
+
+    #[test]
+    fn fuzzy_score_prefers_word_starts_and_runs() {
+        let score = |text| fuzzy_score("git", text).unwrap();
+        assert!(score("GitHub") > score("legit"));
+        assert!(score("my git") > score("legit"));
+        assert!(score("GitHub") > score("gxixt"));
+    }
+
+    #[test]
+    fn filter_rows_ranks_matches() {
+        let rows = vec![
+            row("Example", "me@example.com", "https://example.com"),
+            row("GitHub", "octocat", "https://github.com"),
+            row("Legit shop", "shopper", "https://shop.example"),
+            row("Work", "octocat@work", "https://git.work.example"),
+        ];
+        assert_eq!(filter_rows_by(&rows, "  "), vec![0, 1, 2, 3]);
+        // Every column but the pattern is searched, and ties keep their order
+        assert_eq!(filter_rows_by(&rows, "git"), vec![1, 3, 2]);
+        assert_eq!(filter_rows_by(&rows, "octo"), vec![1, 3]);
+        assert_eq!(filter_rows_by(&rows, "PASSWORD"), Vec::<usize>::new());
+    }
+}
@@ -1,25 +1,348 @@
-use log::{error, info, warn};
-use winapi::_core::{mem, ptr};
-use winapi::um::winuser::RegisterHotKey;
-use winapi::um::winuser::{GetMessageW, UnregisterHotKey, WM_HOTKEY};
-
-pub fn register(modifier: isize, key: i32) {
-    unregister();
-    match unsafe { RegisterHotKey(ptr::null_mut(), 1, modifier as u32, key as u32) } {
-        0 => error!("Failed to register hotkey"),
-        _ => info!("Registered new hotkey"),
+use anyhow::{bail, Result};
+use log::warn;
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+#[cfg(windows)]
+use {
+    log::{error, info},
+    winapi::_core::{mem, ptr},
+    winapi::um::winuser::{self, RegisterHotKey},
+    winapi::um::winuser::{GetMessageW, UnregisterHotKey, WM_HOTKEY},
+};
+
+/// What a hotkey does when pressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Type the login's full autotype pattern
+    Autotype,
+    Username,
+    Password,
+    Totp,
+    /// Like Autotype, but always ask which login to use
+    Picker,
+    /// Type the most recently autotyped login again, the same way as last time
+    RepeatLast,
+    Sync,
+    Lock,
+    /// Add a URI matching the focused window to an existing item
+    Associate,
+    /// Type a newly generated password
+    Generate,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Action::Autotype => "Autotype",
+            Action::Username => "Type username",
+            Action::Password => "Type password",
+            Action::Totp => "Type TOTP",
+            Action::Picker => "Choose login",
+            Action::RepeatLast => "Repeat last autotype",
+            Action::Sync => "Sync vault",
+            Action::Lock => "Lock vault",
+            Action::Associate => "Associate window with login",
+            Action::Generate => "Type generated password",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// A key combination like `Ctrl+Alt+A`, independent of the platform it is registered on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Hotkey {
+    pub modifiers: Modifiers,
+    pub key: Key,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers {
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    pub super_key: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    /// An uppercase ASCII letter
+    Letter(char),
+    /// An ASCII digit
+    Digit(char),
+    /// F1 through F24
+    Function(u8),
+    Space,
+    Enter,
+    Tab,
+    Escape,
+    Backspace,
+    Insert,
+    Delete,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Left,
+    Up,
+    Right,
+    Down,
+}
+
+// X11 modifier masks, see X11/X.h
+const X11_SHIFT_MASK: u32 = 1 << 0;
+const X11_CONTROL_MASK: u32 = 1 << 2;
+const X11_MOD1_MASK: u32 = 1 << 3; // Alt
+const X11_MOD4_MASK: u32 = 1 << 6; // Super
+
+// Named keys with their display name and X11 keysym
+const NAMED_KEYS: &[(Key, &str, u32)] = &[
+    (Key::Space, "Space", 0x0020),
+    (Key::Enter, "Enter", 0xff0d),
+    (Key::Tab, "Tab", 0xff09),
+    (Key::Escape, "Esc", 0xff1b),
+    (Key::Backspace, "Backspace", 0xff08),
+    (Key::Insert, "Insert", 0xff63),
+    (Key::Delete, "Delete", 0xffff),
+    (Key::Home, "Home", 0xff50),
+    (Key::End, "End", 0xff57),
+    (Key::PageUp, "PageUp", 0xff55),
+    (Key::PageDown, "PageDown", 0xff56),
+    (Key::Left, "Left", 0xff51),
+    (Key::Up, "Up", 0xff52),
+    (Key::Right, "Right", 0xff53),
+    (Key::Down, "Down", 0xff54),
+];
+
+// Alternative spellings accepted when parsing
+const KEY_ALIASES: &[(&str, Key)] = &[
+    ("return", Key::Enter),
+    ("escape", Key::Escape),
+    ("del", Key::Delete),
+    ("ins", Key::Insert),
+    ("pgup", Key::PageUp),
+    ("pgdn", Key::PageDown),
+    ("pagedn", Key::PageDown),
+];
+
+#[cfg(windows)]
+impl Hotkey {
+    pub fn win32_modifiers(&self) -> u32 {
+        let m = &self.modifiers;
+        let mut bits = 0;
+        if m.ctrl {
+            bits |= winuser::MOD_CONTROL;
+        }
+        if m.alt {
+            bits |= winuser::MOD_ALT;
+        }
+        if m.shift {
+            bits |= winuser::MOD_SHIFT;
+        }
+        if m.super_key {
+            bits |= winuser::MOD_WIN;
+        }
+        bits as u32
+    }
+
+    pub fn win32_virtual_key(&self) -> u32 {
+        let code = match self.key {
+            // Letters and digits share their virtual key codes with their uppercase ASCII values
+            Key::Letter(c) | Key::Digit(c) => c as i32,
+            Key::Function(n) => winuser::VK_F1 + n as i32 - 1,
+            Key::Space => winuser::VK_SPACE,
+            Key::Enter => winuser::VK_RETURN,
+            Key::Tab => winuser::VK_TAB,
+            Key::Escape => winuser::VK_ESCAPE,
+            Key::Backspace => winuser::VK_BACK,
+            Key::Insert => winuser::VK_INSERT,
+            Key::Delete => winuser::VK_DELETE,
+            Key::Home => winuser::VK_HOME,
+            Key::End => winuser::VK_END,
+            Key::PageUp => winuser::VK_PRIOR,
+            Key::PageDown => winuser::VK_NEXT,
+            Key::Left => winuser::VK_LEFT,
+            Key::Up => winuser::VK_UP,
+            Key::Right => winuser::VK_RIGHT,
+            Key::Down => winuser::VK_DOWN,
+        };
+        code as u32
+    }
+}
+
+// Nothing grabs hotkeys through X11 yet, so only the tests use this for now
+#[cfg_attr(not(test), allow(dead_code))]
+impl Hotkey {
+    pub fn x11_modifier_mask(&self) -> u32 {
+        let m = &self.modifiers;
+        let mut mask = 0;
+        if m.ctrl {
+            mask |= X11_CONTROL_MASK;
+        }
+        if m.alt {
+            mask |= X11_MOD1_MASK;
+        }
+        if m.shift {
+            mask |= X11_SHIFT_MASK;
+        }
+        if m.super_key {
+            mask |= X11_MOD4_MASK;
+        }
+        mask
+    }
+
+    pub fn x11_keysym(&self) -> u32 {
+        match self.key {
+            // Latin-1 keysyms are the lowercase character codes
+            Key::Letter(c) => c.to_ascii_lowercase() as u32,
+            Key::Digit(c) => c as u32,
+            Key::Function(n) => 0xffbe + n as u32 - 1, // XK_F1
+            named => named_key(named).2,
+        }
+    }
+}
+
+fn named_key(key: Key) -> &'static (Key, &'static str, u32) {
+    NAMED_KEYS.iter().find(|(k, ..)| *k == key).unwrap()
+}
+
+impl FromStr for Hotkey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut modifiers = Modifiers::default();
+        let mut key = None;
+
+        for part in s.split('+').map(str::trim) {
+            if key.is_some() {
+                bail!(
+                    "'{}' has to end with exactly one key, after all modifiers",
+                    s
+                );
+            }
+            let flag = match part.to_lowercase().as_str() {
+                "ctrl" | "control" => &mut modifiers.ctrl,
+                "alt" => &mut modifiers.alt,
+                "shift" => &mut modifiers.shift,
+                "super" | "win" | "meta" => &mut modifiers.super_key,
+                _ => {
+                    key = Some(part.parse()?);
+                    continue;
+                }
+            };
+            if *flag {
+                bail!("'{}' appears twice in '{}'", part, s);
+            }
+            *flag = true;
+        }
+
+        match key {
+            Some(key) => Ok(Self { modifiers, key }),
+            None => bail!("'{}' has no key, only modifiers", s),
+        }
+    }
+}
+
+impl TryFrom<String> for Hotkey {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl FromStr for Key {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let lower = s.to_lowercase();
+
+        let mut chars = s.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            if c.is_ascii_alphabetic() {
+                return Ok(Key::Letter(c.to_ascii_uppercase()));
+            } else if c.is_ascii_digit() {
+                return Ok(Key::Digit(c));
+            }
+        }
+
+        if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse().ok()) {
+            if (1..=24).contains(&n) {
+                return Ok(Key::Function(n));
+            }
+        }
+
+        NAMED_KEYS
+            .iter()
+            .find(|(_, name, _)| name.to_lowercase() == lower)
+            .map(|(key, ..)| *key)
+            .or_else(|| {
+                KEY_ALIASES
+                    .iter()
+                    .find(|(alias, _)| *alias == lower)
+                    .map(|(_, key)| *key)
+            })
+            .ok_or_else(|| anyhow::anyhow!("unknown key '{}'", s))
+    }
+}
+
+impl Display for Hotkey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let m = &self.modifiers;
+        let names = [
+            (m.ctrl, "Ctrl"),
+            (m.alt, "Alt"),
+            (m.shift, "Shift"),
+            (m.super_key, "Super"),
+        ];
+        for (_, name) in names.iter().filter(|(set, _)| *set) {
+            write!(f, "{}+", name)?;
+        }
+        write!(f, "{}", self.key)
+    }
+}
+
+impl Display for Key {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Key::Letter(c) | Key::Digit(c) => write!(f, "{}", c),
+            Key::Function(n) => write!(f, "F{}", n),
+            named => write!(f, "{}", named_key(*named).1),
+        }
+    }
+}
+
+/// Registers a hotkey for the calling thread. The id is handed to the `listen` callback.
+#[cfg(windows)]
+pub fn register(id: i32, hotkey: &Hotkey) {
+    unregister(id);
+    match unsafe {
+        RegisterHotKey(
+            ptr::null_mut(),
+            id,
+            hotkey.win32_modifiers(),
+            hotkey.win32_virtual_key(),
+        )
+    } {
+        0 => error!("Failed to register hotkey {}", hotkey),
+        _ => info!("Registered new hotkey {}", hotkey),
     };
 }
 
-fn unregister() {
-    if unsafe { UnregisterHotKey(ptr::null_mut(), 1) } != 0 {
+#[cfg(windows)]
+fn unregister(id: i32) {
+    if unsafe { UnregisterHotKey(ptr::null_mut(), id) } != 0 {
         info!("Unregistered active hotkey");
     };
 }
 
+#[cfg(windows)]
 pub fn listen<C>(mut callback: C)
 where
-    C: FnMut(),
+    C: FnMut(i32),
 {
     let mut msg = unsafe { mem::zeroed() };
     loop {
@@ -27,9 +350,134 @@ where
             0 => warn!("Failed to receive message"),
             _ => {
                 if WM_HOTKEY == msg.message {
-                    callback();
+                    callback(msg.wParam as i32);
                 }
             }
         }
     }
 }
+
+// Global hotkeys need the window system's help, which only Windows' is asked for so far
+#[cfg(not(windows))]
+pub fn register(_id: i32, hotkey: &Hotkey) {
+    warn!(
+        "Global hotkeys aren't supported on this platform, {} does nothing",
+        hotkey
+    );
+}
+
+#[cfg(not(windows))]
+pub fn listen<C>(_callback: C)
+where
+    C: FnMut(i32),
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Hotkey {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_modifiers_and_key() {
+        let hotkey = parse("Ctrl+Alt+A");
+        assert_eq!(
+            hotkey.modifiers,
+            Modifiers {
+                ctrl: true,
+                alt: true,
+                ..Modifiers::default()
+            }
+        );
+        assert_eq!(hotkey.key, Key::Letter('A'));
+    }
+
+    #[test]
+    fn parsing_is_lenient() {
+        assert_eq!(parse(" control + win + a "), parse("Ctrl+Super+A"));
+        assert_eq!(parse("shift+meta+pgdn"), parse("Shift+Super+PageDown"));
+        assert_eq!(parse("Return"), parse("Enter"));
+        assert_eq!(parse("f12").key, Key::Function(12));
+        assert_eq!(parse("7").key, Key::Digit('7'));
+    }
+
+    #[test]
+    fn formats_canonically() {
+        assert_eq!(
+            parse("super+shift+alt+ctrl+x").to_string(),
+            "Ctrl+Alt+Shift+Super+X"
+        );
+        assert_eq!(parse("alt+escape").to_string(), "Alt+Esc");
+        assert_eq!(parse("F1").to_string(), "F1");
+    }
+
+    #[test]
+    fn maps_to_x11() {
+        let hotkey = parse("Ctrl+Alt+F12");
+        assert_eq!(hotkey.x11_modifier_mask(), 0x0c);
+        assert_eq!(hotkey.x11_keysym(), 0xffc9);
+
+        let hotkey = parse("Shift+Super+A");
+        assert_eq!(hotkey.x11_modifier_mask(), 0x41);
+        assert_eq!(hotkey.x11_keysym(), 'a' as u32);
+        assert_eq!(parse("7").x11_keysym(), '7' as u32);
+        assert_eq!(parse("PageDown").x11_keysym(), 0xff56);
+        assert_eq!(parse("Space").x11_keysym(), 0x20);
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut keys: Vec<String> = NAMED_KEYS
+            .iter()
+            .map(|(_, name, _)| name.to_string())
+            .collect();
+        keys.extend(vec!["A".into(), "Z".into(), "0".into(), "F24".into()]);
+        for key in keys {
+            for modifiers in &[
+                "",
+                "Ctrl+",
+                "Ctrl+Alt+",
+                "Shift+Super+",
+                "Ctrl+Alt+Shift+Super+",
+            ] {
+                let text = format!("{}{}", modifiers, key);
+                let hotkey = parse(&text);
+                assert_eq!(hotkey.to_string(), text);
+                assert_eq!(parse(&hotkey.to_string()), hotkey);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        for invalid in &[
+            "",
+            "Ctrl+Alt",
+            "Ctrl+Ctrl+A",
+            "A+Ctrl",
+            "Ctrl+A+B",
+            "Ctrl+",
+            "F0",
+            "F25",
+            "Ctrl+Foo",
+            "Ctrl+!",
+            "Ctrl+ä",
+        ] {
+            assert!(invalid.parse::<Hotkey>().is_err(), "{:?} parsed", invalid);
+        }
+    }
+
+    #[test]
+    fn deserializes_from_strings() {
+        #[derive(Deserialize)]
+        struct Config {
+            hotkey: Hotkey,
+        }
+        let config: Config = toml::from_str("hotkey = 'Ctrl+Shift+F5'").unwrap();
+        assert_eq!(config.hotkey.to_string(), "Ctrl+Shift+F5");
+        assert!(toml::from_str::<Config>("hotkey = 'Ctrl+Shift'").is_err());
+    }
+}
@@ -1,35 +1,35 @@
 #![windows_subsystem = "windows"]
 
 mod bw_cli;
+mod config;
+mod focus;
 mod gui;
 mod hotkeys;
+mod system_events;
+#[cfg(all(test, target_os = "linux"))]
+mod test_bus;
 mod tray;
 mod typing;
+mod window;
 
 use crate::bw_cli::LoginItem;
+use crate::config::{Account, CONFIG};
+use crate::hotkeys::{Action, Hotkey};
+use crate::tray::Update;
 
 use crate::typing::send_raw_string;
+use crate::window::WindowHandle;
 use argh::FromArgs;
+use lazy_static::lazy_static;
 use log::LevelFilter;
-use log::{error, info};
-use std::ffi::OsString;
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use regex::Regex;
+use std::collections::{HashMap, VecDeque};
 use std::io::{stdin, BufRead};
-use std::os::windows::ffi::OsStringExt;
-use std::path::PathBuf;
 use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
-use win_key_codes::VK_A;
-use winapi::shared::minwindef::DWORD;
-use winapi::shared::ntdef::FALSE;
-use winapi::um::processthreadsapi::OpenProcess;
-use winapi::um::psapi::GetProcessImageFileNameW;
-use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
-use winapi::um::winuser::{
-    GetForegroundWindow, GetWindowTextLengthW, GetWindowTextW, GetWindowThreadProcessId, MOD_ALT,
-    MOD_CONTROL,
-};
-
-static DEFAULT_PATTERN: &str = "{USERNAME}{TAB}{PASSWORD}{ENTER}";
 
 fn setup_logger() {
     fern::Dispatch::new()
@@ -44,60 +44,527 @@ fn setup_logger() {
             ))
         })
         .chain(std::io::stdout())
-        .chain(fern::log_file("bitwarden-autotype.log").unwrap())
+        .chain(fern::log_file(&CONFIG.read().log_file).unwrap())
         .apply()
         .unwrap();
 }
 
+static PAUSED: AtomicBool = AtomicBool::new(false);
+static SECRET_PLACEHOLDERS: [&str; 3] = ["{PASSWORD}", "{TOTP}", "{SSH_PASSPHRASE}"];
+
+lazy_static! {
+    static ref LAST_AUTOTYPE: Mutex<Option<(LoginItem, Action)>> = Mutex::new(None);
+    // Accounts, ids and names of recently autotyped items, most recent first. No secrets are
+    // kept here, the item is fetched again when it's used.
+    static ref RECENT: Mutex<VecDeque<(Account, String, String)>> = Mutex::new(VecDeque::new());
+    // Held while asking for the master password, so the tray and a hotkey can't both ask
+    static ref UNLOCK_PROMPT: Mutex<()> = Mutex::new(());
+    static ref GENERATE_PLACEHOLDER: Regex = Regex::new(r"\{GENERATE(?::([^}]*))?\}").unwrap();
+    // Anything that looks like a placeholder, closed or not, to check patterns before saving
+    static ref PLACEHOLDER: Regex = Regex::new(r"(?i)\{([A-Z_]+)([=:][^{}]*)?(\})?").unwrap();
+}
+
+/// Asks the user to unlock the accounts if none of them is unlocked. Returns whether any
+/// account is unlocked now.
+fn ensure_unlocked() -> bool {
+    let _guard = UNLOCK_PROMPT.lock();
+    if bw_cli::any_unlocked() {
+        return true;
+    }
+    let accounts = CONFIG.read().accounts.clone();
+    for account in &accounts {
+        prompt_login(account);
+    }
+    bw_cli::any_unlocked()
+}
+
+/// Asks the user to unlock a single account unless it already is. Returns whether it is
+/// unlocked now.
+fn unlock_account(account: &Account) -> bool {
+    let _guard = UNLOCK_PROMPT.lock();
+    bw_cli::is_unlocked(account) || prompt_login(account)
+}
+
+fn prompt_login(account: &Account) -> bool {
+    match bw_cli::login(account) {
+        Ok(unlocked) => unlocked,
+        Err(e) => {
+            error!("Failed to log into {}: {:?}", account.name, e);
+            gui::alert(&format!("Failed to log into {}: {:#}", account.name, e));
+            false
+        }
+    }
+}
+
 fn listen_to_hotkeys() {
-    hotkeys::register(MOD_ALT | MOD_CONTROL, VK_A);
-    hotkeys::listen(handle_hotkey);
+    // Hotkey ids are the position in this list, plus one since 0 isn't a valid id
+    let bindings: Vec<(Action, Hotkey)> = CONFIG
+        .read()
+        .hotkeys
+        .iter()
+        .map(|(action, hotkey)| (*action, *hotkey))
+        .collect();
+    for (i, (_, hotkey)) in bindings.iter().enumerate() {
+        hotkeys::register(i as i32 + 1, hotkey);
+    }
+
+    hotkeys::listen(|id| match bindings.get((id - 1) as usize) {
+        Some((action, _)) => handle_hotkey(*action),
+        None => warn!("Received unknown hotkey id {}", id),
+    });
+}
+
+/// Locks all accounts and forgets everything that was read from them.
+fn lock_vault() {
+    bw_cli::lock_all();
+    *LAST_AUTOTYPE.lock() = None;
+}
+
+fn lock_account(account: &Account) {
+    bw_cli::lock(account);
+    forget_last_autotype(account);
+}
+
+fn log_out(account: &Account) {
+    bw_cli::logout(account);
+    forget_last_autotype(account);
+    let mut recent = RECENT.lock();
+    recent.retain(|(a, _, _)| a != account);
+    update_recent(&recent);
+}
+
+fn forget_last_autotype(account: &Account) {
+    let mut last = LAST_AUTOTYPE.lock();
+    if matches!(&*last, Some((item, _)) if &item.account == account) {
+        *last = None;
+    }
+}
+
+fn remember_recent(item: &LoginItem) {
+    let mut recent = RECENT.lock();
+    recent.retain(|(account, id, _)| !(account == &item.account && id == &item.id));
+    recent.push_front((item.account.clone(), item.id.clone(), item.name.clone()));
+    recent.truncate(CONFIG.read().recent_items);
+    update_recent(&recent);
+}
+
+fn update_recent(recent: &VecDeque<(Account, String, String)>) {
+    tray::update(Update::Recent(
+        recent.iter().map(|(_, _, name)| name.clone()).collect(),
+    ));
+}
+
+/// Autotypes a recently used item into the window that was focused before the tray was clicked.
+fn autotype_recent(index: usize) {
+    if PAUSED.load(Ordering::SeqCst) {
+        info!("Autotype is paused, ignoring recent item");
+        return;
+    }
+    let (account, id) = match RECENT.lock().get(index) {
+        Some((account, id, _)) => (account.clone(), id.clone()),
+        None => return,
+    };
+    let window_handle = focus::last_target_window();
+    if !unlock_account(&account) {
+        return;
+    }
+    match bw_cli::get_item(&account, &id) {
+        Ok(item) => {
+            if !window::refocus(window_handle) {
+                return;
+            }
+            autotype(
+                &item,
+                Action::Autotype,
+                &ActiveWindowInfo::from_handle(window_handle),
+            );
+        }
+        Err(e) => {
+            error!("Failed to get recent item: {:?}", e);
+            tray::update(Update::Error(Some(format!("Failed to get item: {}", e))));
+        }
+    }
+}
+
+fn toggle_pause() {
+    // fetch_xor flips the flag and returns the old value
+    let paused = !PAUSED.fetch_xor(true, Ordering::SeqCst);
+    info!("Autotype {}", if paused { "paused" } else { "resumed" });
+    tray::update(Update::Paused(paused));
+}
+
+fn lock_expired_sessions() {
+    info!("Starting session expiry thread");
+    loop {
+        std::thread::sleep(Duration::from_secs(15));
+        let config = CONFIG.read();
+        let to_duration = |minutes: Option<u64>| minutes.map(|m| Duration::from_secs(60 * m));
+        let (idle_limit, lifetime) = (
+            to_duration(config.idle_lock_minutes),
+            to_duration(config.session_lifetime_minutes),
+        );
+        drop(config);
+
+        for (account, reason) in bw_cli::expired_sessions(idle_limit, lifetime) {
+            info!("Session of {} expired ({}), locking", account.name, reason);
+            lock_account(&account);
+        }
+    }
+}
+
+fn handle_hotkey(action: Action) {
+    info!("Received hotkey event: {}", action);
+    if action == Action::Lock {
+        lock_vault();
+        return;
+    }
+    if PAUSED.load(Ordering::SeqCst) && !matches!(action, Action::Sync | Action::Associate) {
+        info!("Autotype is paused, ignoring hotkey");
+        return;
+    }
+
+    // Unlocking steals the focus, so remember which window the user wants to autotype into
+    let window_handle = window::foreground();
+    let ready = bw_cli::any_unlocked() || (ensure_unlocked() && window::refocus(window_handle));
+    if !ready {
+        return;
+    }
+    bw_cli::touch();
+
+    match action {
+        Action::Sync => bw_cli::sync(),
+        Action::Associate => associate_window(&ActiveWindowInfo::from_handle(window_handle)),
+        Action::Generate => type_generated_password(window_handle),
+        Action::RepeatLast => {
+            let last = LAST_AUTOTYPE.lock().clone();
+            match last {
+                Some((item, action)) => {
+                    autotype(&item, action, &ActiveWindowInfo::from_handle(window_handle))
+                }
+                None => info!("Nothing has been autotyped yet"),
+            }
+        }
+        _ => autotype_focused_window(action, window_handle),
+    }
 }
 
-fn handle_hotkey() {
-    info!("Received hotkey event");
-    let window_info = ActiveWindowInfo::new();
-    match bw_cli::list_logins(window_info) {
+fn autotype_focused_window(action: Action, window_handle: WindowHandle) {
+    let window_info = ActiveWindowInfo::from_handle(window_handle);
+    let logins = match focus::cached_logins(&window_info) {
+        Some(logins) => Ok(logins),
+        None => bw_cli::list_logins(&window_info),
+    };
+    match logins {
         Ok(logins) => {
             match logins.len() {
+                0 if CONFIG.read().offer_new_login => offer_new_login(&window_info, None),
                 0 => error!("Bitwarden returned no matching logins"),
-                1 => autotype(logins.get(0).unwrap()),
-                _ => match gui::login_choice(logins) {
-                    Ok(item) => autotype(&item),
+                1 if action != Action::Picker => autotype(&logins[0], action, &window_info),
+                _ => match gui::login_choice("Choose a Login", logins, Some(save_pattern)) {
+                    Ok(Some(item)) => autotype(&item, action, &window_info),
+                    Ok(None) => info!("Login choice cancelled"),
                     Err(e) => error!("Failed to ask the user to choose a login: {:?}", e),
                 },
             };
         }
-        Err(e) => error!("Failed to get logins: {:?}", e),
+        Err(e) => {
+            error!("Failed to get logins: {:?}", e);
+            tray::update(Update::Error(Some(format!("Failed to get logins: {}", e))));
+        }
     };
 }
 
-fn autotype(item: &LoginItem) {
-    info!("Autotype for {}", item.name);
+/// Checks the pattern and saves it to the item's pattern field, or to its notes if it already
+/// keeps a pattern line there. An empty pattern goes back to the default.
+fn save_pattern(item: &LoginItem, pattern: &str) -> anyhow::Result<LoginItem> {
+    validate_pattern(pattern)?;
+    bw_cli::set_autotype_pattern(item, pattern)
+}
 
-    let mut pattern = item
-        .autotype_pattern()
-        .unwrap_or_else(|| DEFAULT_PATTERN.to_string());
+fn validate_pattern(pattern: &str) -> anyhow::Result<()> {
+    if pattern.contains(['\r', '\n']) {
+        anyhow::bail!("Patterns are a single line, use {{ENTER}} to hit enter");
+    }
+    for captures in PLACEHOLDER.captures_iter(pattern) {
+        let placeholder = &captures[0];
+        let name = &captures[1];
+        let argument = captures.get(2).map(|m| &m.as_str()[1..]);
+        if captures.get(3).is_none() {
+            anyhow::bail!("{} is missing its closing }}", placeholder);
+        }
+        if name != name.to_uppercase() {
+            anyhow::bail!(
+                "Placeholders are upper case, like {}",
+                placeholder.to_uppercase()
+            );
+        }
+        match (name, argument) {
+            ("USERNAME", None)
+            | ("PASSWORD", None)
+            | ("TOTP", None)
+            | ("TAB", None)
+            | ("ENTER", None)
+            | ("SSH_PUBLIC_KEY", None)
+            | ("SSH_FINGERPRINT", None)
+            | ("SSH_PASSPHRASE", None)
+            | ("GENERATE", None) => {}
+            ("SLEEP", Some(millis)) if placeholder.starts_with("{SLEEP=") => {
+                millis.parse::<u64>().map_err(|_| {
+                    anyhow::anyhow!("{} needs a number of milliseconds", placeholder)
+                })?;
+            }
+            ("GENERATE", Some(options)) if placeholder.starts_with("{GENERATE:") => {
+                options.parse::<bw_cli::GeneratorOptions>()?;
+            }
+            _ => anyhow::bail!("Unknown placeholder {}", placeholder),
+        }
+    }
+    Ok(())
+}
+
+/// Associates the window that was focused before the tray was clicked with an item.
+fn associate_last_window() {
+    let window_handle = focus::last_target_window();
+    if ensure_unlocked() {
+        associate_window(&ActiveWindowInfo::from_handle(window_handle));
+    }
+}
+
+/// Lets the user search the vault for an item and adds a URI matching the window to it.
+fn associate_window(window_info: &ActiveWindowInfo) {
+    info!("Associating {:?} with an item", window_info);
+    let items = match bw_cli::list_all_logins() {
+        Ok(items) => items,
+        Err(e) => {
+            error!("Failed to list items: {:?}", e);
+            tray::update(Update::Error(Some(format!("Failed to list items: {}", e))));
+            return;
+        }
+    };
+    let title = format!("Associate \"{}\" with", window_info.title);
+    let item = match gui::login_choice(&title, items, None) {
+        Ok(Some(item)) => item,
+        Ok(None) => {
+            info!("Association cancelled");
+            return;
+        }
+        Err(e) => {
+            error!("Failed to ask the user to choose a login: {:?}", e);
+            return;
+        }
+    };
+
+    let suggestions = vec![
+        ("Exact window title", window_info.title.clone(), Some(3)),
+        (
+            "Window title pattern",
+            bw_cli::title_regex(&window_info.title),
+            Some(4),
+        ),
+        ("Executable", window_info.executable.clone(), Some(3)),
+    ];
+    match gui::uri_dialog(&item.name, &suggestions) {
+        Ok(Some((uri, match_type))) => {
+            if let Err(e) = bw_cli::add_uri(&item, &uri, match_type) {
+                error!("Failed to add URI: {:?}", e);
+                gui::alert(&format!("Failed to add the URI to {}: {:#}", item.name, e));
+            }
+        }
+        Ok(None) => info!("Association cancelled"),
+        Err(e) => error!("Failed to ask for a URI: {:?}", e),
+    }
+}
+
+/// Lets the user save a password that was generated while typing into the window, either as a
+/// new login or as the new password of the item it was typed for.
+fn offer_save_generated(
+    window_info: &ActiveWindowInfo,
+    item: Option<&LoginItem>,
+    password: String,
+) {
+    let item = match item {
+        Some(item) => item,
+        None => {
+            offer_new_login(window_info, Some(password));
+            return;
+        }
+    };
+    match gui::save_generated_choice(&item.name) {
+        Some(gui::SaveGenerated::NewLogin) => offer_new_login(window_info, Some(password)),
+        Some(gui::SaveGenerated::UpdateItem) => {
+            if let Err(e) = bw_cli::update_password(item, &password) {
+                error!("Failed to update password: {:?}", e);
+                gui::alert(&format!(
+                    "Failed to update the password of {}: {:#}",
+                    item.name, e
+                ));
+            }
+        }
+        None => info!("Not saving the generated password"),
+    }
+}
+
+/// Asks for generator options, then types a password generated with them and offers to save it.
+fn type_generated_password(window_handle: WindowHandle) {
+    // Generating doesn't touch the vault, so any account's bw will do
+    let account = match bw_cli::unlocked_accounts().into_iter().next() {
+        Some(account) => account,
+        None => return,
+    };
+    let mut options = bw_cli::STRONG_PASSWORD.to_string();
+    let password = loop {
+        options = match gui::prompt_generator_options(&options) {
+            Some(options) => options,
+            None => {
+                info!("Password generation cancelled");
+                return;
+            }
+        };
+        match options
+            .parse::<bw_cli::GeneratorOptions>()
+            .and_then(|o| bw_cli::generate(&account, &o))
+        {
+            Ok(password) => break password,
+            Err(e) => gui::alert(&format!("Failed to generate a password: {:#}", e)),
+        }
+    };
+
+    if !window::refocus(window_handle) {
+        return;
+    }
+    send_raw_string(password.clone());
+
+    let window_info = ActiveWindowInfo::from_handle(window_handle);
+    // Only offer to update a login if it's clear which one
+    let logins = bw_cli::list_logins(&window_info).unwrap_or_default();
+    offer_save_generated(
+        &window_info,
+        logins.first().filter(|_| logins.len() == 1),
+        password,
+    );
+}
+
+/// Replaces every `{GENERATE}` or `{GENERATE:options}` with a generated password. Placeholders
+/// with the same options get the same password, so it can be typed into a confirmation field
+/// too. Returns the pattern and the first generated password, if any.
+fn fill_generated(pattern: &str, account: &Account) -> anyhow::Result<(String, Option<String>)> {
+    let mut generated: HashMap<String, String> = HashMap::new();
+    let mut first = None;
+    let mut filled = String::new();
+    let mut last_end = 0;
+    for captures in GENERATE_PLACEHOLDER.captures_iter(pattern) {
+        let placeholder = captures.get(0).unwrap();
+        let options = captures
+            .get(1)
+            .map_or("", |m| m.as_str())
+            .trim()
+            .to_string();
+        let password = match generated.get(&options) {
+            Some(password) => password.clone(),
+            None => {
+                let password = bw_cli::generate(account, &options.parse()?)?;
+                generated.insert(options, password.clone());
+                password
+            }
+        };
+        first.get_or_insert_with(|| password.clone());
+        filled.push_str(&pattern[last_end..placeholder.start()]);
+        filled.push_str(&password);
+        last_end = placeholder.end();
+    }
+    filled.push_str(&pattern[last_end..]);
+    Ok((filled, first))
+}
+
+/// Lets the user save a new login for a window nothing matched.
+fn offer_new_login(window_info: &ActiveWindowInfo, password: Option<String>) {
+    info!("Offering to save a new login");
+    let accounts = bw_cli::unlocked_accounts();
+    let names: Vec<String> = accounts.iter().map(|a| a.name.clone()).collect();
+    let generate_accounts = accounts.clone();
+    let result = gui::new_login_dialog(
+        &names,
+        &window_info.title,
+        &window_info.executable,
+        &password.unwrap_or_default(),
+        move |i| {
+            let options = bw_cli::STRONG_PASSWORD.parse()?;
+            bw_cli::generate(&generate_accounts[i], &options)
+        },
+        validate_pattern,
+    );
+    match result {
+        Ok(Some((i, login))) => {
+            if let Err(e) = bw_cli::create_login(&accounts[i], login) {
+                error!("Failed to create login: {:?}", e);
+                gui::alert(&format!("Failed to save the login: {:#}", e));
+            }
+        }
+        Ok(None) => info!("Saving a new login cancelled"),
+        Err(e) => error!("Failed to ask for a new login: {:?}", e),
+    }
+}
+
+/// Types the item into the window it was chosen for. `window_info` has to be read before any
+/// picker or prompt could take the focus.
+fn autotype(item: &LoginItem, action: Action, window_info: &ActiveWindowInfo) {
+    info!("{} for {}", action, item.name);
+
+    let mut pattern = match action {
+        Action::Username => "{USERNAME}".to_string(),
+        Action::Password => "{PASSWORD}".to_string(),
+        Action::Totp => "{TOTP}".to_string(),
+        _ => item.effective_pattern(Some(window_info)),
+    };
+
+    // Bitwarden only protects the secrets, the username can be typed without asking
+    if item.needs_reprompt()
+        && SECRET_PLACEHOLDERS.iter().any(|p| pattern.contains(p))
+        && !confirm_reprompt(item)
+    {
+        return;
+    }
+    // Only now, so a cancelled reprompt can't be repeated or picked from the tray instead
+    *LAST_AUTOTYPE.lock() = Some((item.clone(), action));
+    remember_recent(item);
+
+    // First, so that nothing a password is made of can be mistaken for a placeholder
+    let generated = match fill_generated(&pattern, &item.account) {
+        Ok((filled, generated)) => {
+            pattern = filled;
+            generated
+        }
+        Err(e) => {
+            error!("Failed to generate password: {:?}", e);
+            tray::update(Update::Error(Some(format!(
+                "Failed to generate password: {}",
+                e
+            ))));
+            return;
+        }
+    };
 
     pattern = pattern.replace(
         "{USERNAME}",
         &item
             .login
             .as_ref()
-            .map(|l| l.username.clone())
-            .flatten()
-            .unwrap_or_else(|| "".to_string()),
+            .and_then(|l| l.username.clone())
+            .unwrap_or_default(),
     );
     pattern = pattern.replace(
         "{PASSWORD}",
         &item
             .login
             .as_ref()
-            .map(|l| l.password.clone())
-            .flatten()
-            .unwrap_or_else(|| "".to_string()),
+            .and_then(|l| l.password.clone())
+            .unwrap_or_default(),
     );
 
+    pattern = pattern.replace("{SSH_PUBLIC_KEY}", &item.ssh_public_key());
+    pattern = pattern.replace("{SSH_FINGERPRINT}", &item.ssh_fingerprint());
+    pattern = pattern.replace("{SSH_PASSPHRASE}", &item.ssh_passphrase());
+
     if pattern.contains("{TOTP}") {
         // Check first, because getting the code is expensive
         match item.totp() {
@@ -109,43 +576,42 @@ fn autotype(item: &LoginItem) {
         }
     }
     send_raw_string(pattern);
+
+    if let Some(password) = generated {
+        offer_save_generated(window_info, Some(item), password);
+    }
 }
 
+/// Asks for the master password of the item's account. Returns whether it was entered correctly
+/// and the window that had the focus before has it again.
+fn confirm_reprompt(item: &LoginItem) -> bool {
+    let window_handle = window::foreground();
+    let mut message = format!("Enter your master password to use {}", item.name);
+    loop {
+        match gui::prompt_master_password(&message) {
+            Some(password) if bw_cli::verify_master_password(&item.account, &password) => break,
+            Some(_) => message = format!("Wrong master password, try again to use {}", item.name),
+            None => {
+                info!("Master password prompt for {} cancelled", item.name);
+                return false;
+            }
+        }
+    }
+    window::refocus(window_handle)
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct ActiveWindowInfo {
     title: String,
     executable: String,
 }
 
 impl ActiveWindowInfo {
-    fn new() -> Self {
-        let window_handle = unsafe { GetForegroundWindow() }; // First, get the window handle
-        let title_len = unsafe { GetWindowTextLengthW(window_handle) } + 1; // Get the title length (+1 to be sure)
-
-        let mut buffer: Vec<u16> = Vec::with_capacity(title_len as usize); // Create a buffer that windows can fill
-        let read_len = unsafe { GetWindowTextW(window_handle, buffer.as_mut_ptr(), title_len) }; // Tell windows to fill the buffer
-
-        // Tell the buffer how much has been read into it, lest it still thinks it's empty, resulting in an empty string
-        unsafe { buffer.set_len(read_len as usize) };
-        let title = String::from_utf16_lossy(buffer.as_slice());
-
-        let mut pid = 0;
-        unsafe { GetWindowThreadProcessId(window_handle, &mut pid) }; // Get the process id
-        let psapi_handle =
-            unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE as i32, pid) }; // Get a PSAPI handle, limited information permission is sufficient
-
-        let mut buffer = [0; 1024]; // Same as above, except we have to guess the capacity, and we have to use a slice for... reasons?
-        let read_len = unsafe {
-            GetProcessImageFileNameW(psapi_handle, buffer.as_mut_ptr(), buffer.len() as DWORD)
-        };
-        let executable_path: PathBuf = OsString::from_wide(&buffer[..read_len as usize]).into();
-
-        let executable = executable_path
-            .file_name()
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
-
-        Self { title, executable }
+    fn from_handle(window_handle: WindowHandle) -> Self {
+        Self {
+            title: window::title(window_handle),
+            executable: window::executable(window_handle),
+        }
     }
 }
 
@@ -160,29 +626,39 @@ struct BitwardenAutotype {
 
 fn main() {
     let opts: BitwardenAutotype = argh::from_env();
+    if let Err(e) = config::load() {
+        eprintln!("{:#}", e);
+        gui::alert(&format!("{:#}", e));
+        exit(1);
+    }
     setup_logger();
 
     if opts.server {
         run_as_server();
     }
 
-    bw_cli::login().unwrap();
-
     std::thread::spawn(listen_to_hotkeys);
 
+    std::thread::spawn(lock_expired_sessions);
+    if CONFIG.read().lock_on_system_lock {
+        std::thread::spawn(system_events::watch);
+    }
+
+    let match_indicator = CONFIG.read().match_indicator;
+    std::thread::spawn(move || focus::watch(match_indicator));
+
     std::thread::spawn(|| {
         info!("Starting Syncing thread");
         loop {
-            bw_cli::sync();
-            std::thread::sleep(Duration::from_secs(60 * 5));
+            if bw_cli::any_unlocked() {
+                bw_cli::sync();
+            }
+            let interval = CONFIG.read().sync_interval_minutes;
+            std::thread::sleep(Duration::from_secs(60 * interval));
         }
     });
 
-    let email = bw_cli::EMAIL
-        .read()
-        .clone()
-        .unwrap_or_else(|| "(unknown)".into());
-    tray::main(email);
+    tray::main();
 }
 
 fn run_as_server() {
@@ -194,3 +670,49 @@ fn run_as_server() {
     }
     exit(0);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_valid_patterns() {
+        for pattern in &[
+            "",
+            "{USERNAME}{TAB}{PASSWORD}{ENTER}",
+            "{TOTP}{SLEEP=500}{ENTER}",
+            "{SSH_PASSPHRASE}{ENTER}",
+            "{SSH_PUBLIC_KEY} {SSH_FINGERPRINT}",
+            "{GENERATE}{TAB}{GENERATE:length=30,special}",
+            "plain text and a lone } brace",
+        ] {
+            if let Err(e) = validate_pattern(pattern) {
+                panic!("{:?} was rejected: {}", pattern, e);
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for pattern in &[
+            "{USERNAME}\n{PASSWORD}",
+            "{PASSWORD}\r",
+            "{PASSWORD",
+            "{password}",
+            "{PASWORD}",
+            "{SLEEP}",
+            "{SLEEP=soon}",
+            "{SLEEP:500}",
+            "{TAB=2}",
+            "{GENERATE=length=5}",
+            "{GENERATE:length=many}",
+            "{GENERATE:colour}",
+        ] {
+            assert!(
+                validate_pattern(pattern).is_err(),
+                "{:?} was accepted",
+                pattern
+            );
+        }
+    }
+}
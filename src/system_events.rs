@@ -0,0 +1,256 @@
+//! Locks the vault when the desktop session is locked or the system goes to sleep.
+
+use log::info;
+
+fn on_lock_event(reason: &str) {
+    if crate::bw_cli::any_unlocked() {
+        info!("Locking vault, {}", reason);
+        crate::lock_vault();
+    }
+}
+
+#[cfg(windows)]
+pub use self::windows::watch;
+
+#[cfg(target_os = "linux")]
+pub use self::linux::watch;
+
+#[cfg(windows)]
+mod windows {
+    use log::{error, info, warn};
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use winapi::_core::{mem, ptr};
+    use winapi::shared::minwindef::{BOOL, DWORD, LPARAM, LRESULT, UINT, WPARAM};
+    use winapi::shared::windef::HWND;
+    use winapi::um::libloaderapi::GetModuleHandleW;
+    use winapi::um::winuser::{
+        CreateWindowExW, DefWindowProcW, DispatchMessageW, GetMessageW, RegisterClassW,
+        TranslateMessage, PBT_APMSUSPEND, WM_POWERBROADCAST, WM_WTSSESSION_CHANGE, WNDCLASSW,
+        WTS_SESSION_LOCK,
+    };
+
+    // winapi doesn't have these, see WtsApi32.h
+    const NOTIFY_FOR_THIS_SESSION: DWORD = 0;
+
+    #[link(name = "wtsapi32")]
+    extern "system" {
+        fn WTSRegisterSessionNotification(window_handle: HWND, flags: DWORD) -> BOOL;
+    }
+
+    /// Listens for session lock and suspend notifications. Blocks forever, so this should be
+    /// run in its own thread.
+    pub fn watch() {
+        let class_name: Vec<u16> = OsStr::new("BitwardenAutotypeSystemEvents")
+            .encode_wide()
+            .chain(Some(0))
+            .collect();
+
+        // Notifications are only sent to windows, so we create one that is never shown.
+        // It can't be a message-only window, since those don't receive WM_POWERBROADCAST.
+        let window_handle = unsafe {
+            let instance = GetModuleHandleW(ptr::null());
+            let class = WNDCLASSW {
+                lpfnWndProc: Some(window_proc),
+                hInstance: instance,
+                lpszClassName: class_name.as_ptr(),
+                ..mem::zeroed()
+            };
+            RegisterClassW(&class);
+            CreateWindowExW(
+                0,
+                class_name.as_ptr(),
+                class_name.as_ptr(),
+                0,
+                0,
+                0,
+                0,
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                instance,
+                ptr::null_mut(),
+            )
+        };
+        if window_handle.is_null() {
+            error!("Failed to create window for system event notifications");
+            return;
+        }
+        if unsafe { WTSRegisterSessionNotification(window_handle, NOTIFY_FOR_THIS_SESSION) } == 0 {
+            error!("Failed to register for session change notifications");
+        }
+        info!("Watching for session locks and suspends");
+
+        let mut msg = unsafe { mem::zeroed() };
+        loop {
+            match unsafe { GetMessageW(&mut msg, ptr::null_mut(), 0, 0) } {
+                0 => warn!("Failed to receive message"),
+                _ => unsafe {
+                    TranslateMessage(&msg);
+                    DispatchMessageW(&msg);
+                },
+            }
+        }
+    }
+
+    unsafe extern "system" fn window_proc(
+        window_handle: HWND,
+        msg: UINT,
+        w_param: WPARAM,
+        l_param: LPARAM,
+    ) -> LRESULT {
+        match (msg, w_param) {
+            (WM_WTSSESSION_CHANGE, WTS_SESSION_LOCK) => super::on_lock_event("session locked"),
+            (WM_POWERBROADCAST, PBT_APMSUSPEND) => super::on_lock_event("system suspending"),
+            _ => {}
+        }
+        DefWindowProcW(window_handle, msg, w_param, l_param)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use log::{error, info, warn};
+    use zbus::blocking::{Connection, Proxy};
+    use zbus::zvariant::OwnedObjectPath;
+
+    static LOGIND: &str = "org.freedesktop.login1";
+
+    /// Listens for logind's `Lock` and `PrepareForSleep` signals on the system bus.
+    /// Blocks forever, so this should be run in its own thread.
+    ///
+    /// The bus can be swapped for a stand-in by setting `DBUS_SYSTEM_BUS_ADDRESS`.
+    pub fn watch() {
+        if let Err(e) = Connection::system().and_then(|c| watch_logind(&c)) {
+            error!(
+                "Failed to watch logind for session locks and suspends: {}",
+                e
+            );
+        }
+    }
+
+    fn watch_logind(connection: &Connection) -> zbus::Result<()> {
+        let manager: Proxy<'static> = Proxy::new(
+            connection,
+            LOGIND,
+            "/org/freedesktop/login1",
+            "org.freedesktop.login1.Manager",
+        )?;
+
+        let sleep_signals = manager.receive_signal("PrepareForSleep")?;
+        std::thread::spawn(move || {
+            for signal in sleep_signals {
+                // The argument is true before suspending and false after resuming
+                if signal.body::<bool>().unwrap_or(false) {
+                    super::on_lock_event("system suspending");
+                }
+            }
+        });
+
+        let session_path: OwnedObjectPath =
+            match manager.call("GetSessionByPID", &(std::process::id())) {
+                Ok(path) => path,
+                Err(e) => {
+                    warn!(
+                        "Not running in a logind session, only watching for suspends: {}",
+                        e
+                    );
+                    return Ok(());
+                }
+            };
+        let session: Proxy<'static> = Proxy::new(
+            connection,
+            LOGIND,
+            session_path,
+            "org.freedesktop.login1.Session",
+        )?;
+        info!("Watching for session locks and suspends");
+        for _ in session.receive_signal("Lock")? {
+            super::on_lock_event("session locked");
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::bw_cli;
+        use crate::config::CONFIG;
+        use crate::test_bus::TestBus;
+        use std::convert::TryFrom;
+        use std::time::{Duration, Instant};
+        use zbus::dbus_interface;
+
+        static MANAGER_PATH: &str = "/org/freedesktop/login1";
+        static SESSION_PATH: &str = "/org/freedesktop/login1/session/_31";
+
+        struct Manager;
+
+        #[dbus_interface(name = "org.freedesktop.login1.Manager")]
+        impl Manager {
+            #[dbus_interface(name = "GetSessionByPID")]
+            fn get_session_by_pid(&self, _pid: u32) -> OwnedObjectPath {
+                OwnedObjectPath::try_from(SESSION_PATH).unwrap()
+            }
+        }
+
+        // Signals are sent until the watcher has subscribed and reacted to one
+        fn emit_until_locked<B: serde::Serialize + zbus::zvariant::DynamicType>(
+            logind: &Connection,
+            path: &str,
+            interface: &str,
+            signal: &str,
+            body: &B,
+        ) -> bool {
+            let start = Instant::now();
+            while bw_cli::any_unlocked() && start.elapsed() < Duration::from_secs(10) {
+                logind
+                    .emit_signal(None::<()>, path, interface, signal, body)
+                    .unwrap();
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            !bw_cli::any_unlocked()
+        }
+
+        #[test]
+        fn locks_on_logind_signals() {
+            let bus = match TestBus::start() {
+                Some(bus) => bus,
+                None => return,
+            };
+            let _candidates = crate::focus::tests::CANDIDATES_LOCK.lock();
+            // Locking calls bw, which must not touch a real vault
+            CONFIG.write().bw_path = "/nonexistent/bw".into();
+            let account = CONFIG.read().accounts[0].clone();
+
+            let logind = bus
+                .builder()
+                .name(LOGIND)
+                .unwrap()
+                .serve_at(MANAGER_PATH, Manager)
+                .unwrap()
+                .build()
+                .unwrap();
+            let watcher = bus.builder().build().unwrap();
+            std::thread::spawn(move || watch_logind(&watcher));
+
+            bw_cli::insert_test_session(&account);
+            assert!(emit_until_locked(
+                &logind,
+                MANAGER_PATH,
+                "org.freedesktop.login1.Manager",
+                "PrepareForSleep",
+                &true,
+            ));
+
+            bw_cli::insert_test_session(&account);
+            assert!(emit_until_locked(
+                &logind,
+                SESSION_PATH,
+                "org.freedesktop.login1.Session",
+                "Lock",
+                &(),
+            ));
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! A private D-Bus daemon for tests, so they never touch the user's session or system bus.
+
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use zbus::blocking::ConnectionBuilder;
+
+static NEXT_BUS: AtomicUsize = AtomicUsize::new(0);
+
+pub struct TestBus {
+    daemon: Child,
+    dir: PathBuf,
+    address: String,
+}
+
+impl TestBus {
+    /// Starts a bus that lets everyone own any name. Returns `None` if dbus-daemon isn't
+    /// installed, so tests can skip themselves.
+    pub fn start() -> Option<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "{}-test-bus-{}-{}",
+            env!("CARGO_PKG_NAME"),
+            std::process::id(),
+            NEXT_BUS.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("bus.conf");
+        std::fs::write(
+            &config,
+            format!(
+                r#"<busconfig>
+  <type>session</type>
+  <listen>unix:path={}</listen>
+  <auth>EXTERNAL</auth>
+  <policy context="default">
+    <allow send_destination="*" eavesdrop="true"/>
+    <allow eavesdrop="true"/>
+    <allow own="*"/>
+  </policy>
+</busconfig>"#,
+                dir.join("socket").display()
+            ),
+        )
+        .unwrap();
+
+        let mut daemon = match Command::new("dbus-daemon")
+            .arg(format!("--config-file={}", config.display()))
+            .args(["--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(daemon) => daemon,
+            Err(e) => {
+                eprintln!("Skipping, dbus-daemon couldn't be started: {}", e);
+                let _ = std::fs::remove_dir_all(&dir);
+                return None;
+            }
+        };
+        // The address is printed once the daemon is listening
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Some(Self {
+            daemon,
+            dir,
+            address: address.trim().to_string(),
+        })
+    }
+
+    pub fn builder(&self) -> ConnectionBuilder<'static> {
+        ConnectionBuilder::address(self.address.as_str()).unwrap()
+    }
+}
+
+impl Drop for TestBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
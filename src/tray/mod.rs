@@ -0,0 +1,425 @@
+use crate::bw_cli::{Status, VaultStatus};
+use crate::config::CONFIG;
+use lazy_static::lazy_static;
+use log::{error, info};
+use parking_lot::Mutex;
+use std::process::exit;
+use std::sync::mpsc::Sender;
+
+#[cfg(target_os = "linux")]
+mod sni;
+#[cfg(windows)]
+mod windows;
+
+#[cfg(target_os = "linux")]
+use self::sni as backend;
+#[cfg(windows)]
+use self::windows as backend;
+
+static TOOLTIP: &str = "Bitwarden Autotype";
+
+lazy_static! {
+    static ref UPDATES: Mutex<Option<Sender<Update>>> = Mutex::new(None);
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Events {
+    Exit,
+    // Only Windows' tray needs an event for disabled items
+    #[cfg_attr(not(windows), allow(dead_code))]
+    Dummy,
+    SyncNow,
+    /// Unlock all accounts if none is unlocked
+    UnlockAll,
+    LockAll,
+    /// These refer to the n-th configured account
+    Unlock(usize),
+    Lock(usize),
+    LogOut(usize),
+    SwitchAccount(usize),
+    TogglePause,
+    Associate,
+    /// Autotype the n-th recently used item
+    Recent(usize),
+}
+
+/// Changes to the tray icon requested by other threads.
+#[derive(Debug)]
+pub enum Update {
+    /// The number of logins matching the focused window
+    Matches(usize),
+    /// The status of every configured account, in config order
+    Status(Vec<Status>),
+    Paused(bool),
+    /// The most recent error worth showing the user, or None once it is resolved
+    Error(Option<String>),
+    /// Names of the recently autotyped items, most recent first
+    Recent(Vec<String>),
+}
+
+/// Everything the menu and tooltip are built from.
+#[derive(Default)]
+struct TrayState {
+    status: Option<Vec<Status>>,
+    matches: usize,
+    paused: bool,
+    error: Option<String>,
+    recent: Vec<String>,
+}
+
+impl TrayState {
+    fn apply(&mut self, update: Update) {
+        match update {
+            Update::Matches(n) => self.matches = n,
+            Update::Status(status) => self.status = Some(status),
+            Update::Paused(paused) => self.paused = paused,
+            Update::Error(error) => self.error = error,
+            Update::Recent(recent) => self.recent = recent,
+        }
+    }
+}
+
+/// A platform-neutral menu, which the backends turn into whatever their tray expects.
+#[derive(Clone, Debug)]
+enum Entry {
+    /// A disabled item that only shows some text
+    Info(String),
+    Item(String, Events),
+    Checkable(String, bool, Events),
+    Submenu(String, Vec<Entry>),
+    Separator,
+}
+
+/// Queues an update for the tray icon, which is applied by the thread owning it.
+pub fn update(update: Update) {
+    if let Some(s) = UPDATES.lock().as_ref() {
+        let _ = s.send(update);
+    }
+}
+
+/// Asks bw for the current status of every account and updates the tray with it.
+pub fn refresh() {
+    let accounts = CONFIG.read().accounts.clone();
+    match accounts
+        .iter()
+        .map(crate::bw_cli::status)
+        .collect::<anyhow::Result<Vec<_>>>()
+    {
+        Ok(status) => update(Update::Status(status)),
+        Err(e) => error!("Failed to refresh status: {:?}", e),
+    }
+}
+
+fn tooltip(state: &TrayState) -> String {
+    let unlocked = |s: &&Status| s.vault_status == VaultStatus::Unlocked;
+    let vault = match &state.status {
+        _ if state.paused => "Paused".into(),
+        None => "Starting".into(),
+        Some(status) if status.len() > 1 => format!(
+            "{}/{} unlocked",
+            status.iter().filter(unlocked).count(),
+            status.len()
+        ),
+        Some(status) => match status.first().map(|s| &s.vault_status) {
+            Some(VaultStatus::Unlocked) => "Unlocked".into(),
+            Some(VaultStatus::Locked) => "Locked".into(),
+            _ => "Logged out".into(),
+        },
+    };
+    let mut tooltip = format!("{} - {}", TOOLTIP, vault);
+    if state.error.is_some() {
+        tooltip.push_str(" (Error)");
+    } else if state.matches > 0 {
+        tooltip.push_str(&format!(" ({} matching logins)", state.matches));
+    }
+    tooltip
+}
+
+/// The info lines and the actions of one account.
+fn account_menu(index: usize, status: &Status, single: bool) -> (Vec<Entry>, Vec<Entry>) {
+    let mut info = vec![Entry::Info(match &status.user_email {
+        Some(email) => format!("Logged in as {}", email),
+        None => "Not logged in".into(),
+    })];
+    let server = status.server_url.as_deref().unwrap_or("bitwarden.com");
+    info.push(Entry::Info(format!("Server: {}", server)));
+    let last_sync = status.last_sync.map_or("never".into(), |t| {
+        t.with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M")
+            .to_string()
+    });
+    info.push(Entry::Info(format!("Last synced: {}", last_sync)));
+
+    let mut menu = vec![];
+    match status.vault_status {
+        VaultStatus::Unlocked => menu.push(Entry::Item("Lock".into(), Events::Lock(index))),
+        VaultStatus::Unauthenticated => {
+            menu.push(Entry::Item("Log in".into(), Events::Unlock(index)))
+        }
+        VaultStatus::Locked => menu.push(Entry::Item("Unlock".into(), Events::Unlock(index))),
+    };
+    if status.vault_status != VaultStatus::Unauthenticated {
+        menu.push(Entry::Item("Log out".into(), Events::LogOut(index)));
+        // With several accounts, each one is meant to stay the account it's configured as
+        if single {
+            menu.push(Entry::Item(
+                "Switch Account".into(),
+                Events::SwitchAccount(index),
+            ));
+        }
+    }
+    (info, menu)
+}
+
+fn menu(state: &TrayState) -> Vec<Entry> {
+    let config = CONFIG.read();
+    let mut menu = vec![
+        Entry::Info("Bitwarden Autotype is running".into()),
+        Entry::Separator,
+    ];
+
+    let statuses = state.status.as_deref().unwrap_or(&[]);
+    let single = config.accounts.len() == 1;
+    let mut account_entries = vec![];
+    for (i, (account, status)) in config.accounts.iter().zip(statuses).enumerate() {
+        let (info, actions) = account_menu(i, status, single);
+        if single {
+            // Keep the account's info at the top and its actions with the others
+            menu.extend(info);
+            account_entries = actions;
+        } else {
+            let vault = match status.vault_status {
+                VaultStatus::Unlocked => "unlocked",
+                VaultStatus::Locked => "locked",
+                VaultStatus::Unauthenticated => "logged out",
+            };
+            let entries = info
+                .into_iter()
+                .chain(Some(Entry::Separator))
+                .chain(actions)
+                .collect();
+            menu.push(Entry::Submenu(
+                format!("{} ({})", account.name, vault),
+                entries,
+            ));
+        }
+    }
+    if let Some(error) = &state.error {
+        menu.push(Entry::Info(format!("Error: {}", error)));
+    }
+    menu.push(Entry::Separator);
+
+    for (action, hotkey) in &config.hotkeys {
+        menu.push(Entry::Info(format!("{}: {}", action, hotkey)));
+    }
+    menu.push(Entry::Info(format!(
+        "Syncing every {} min",
+        config.sync_interval_minutes
+    )));
+    menu.push(Entry::Separator);
+
+    let any_unlocked = statuses
+        .iter()
+        .any(|s| s.vault_status == VaultStatus::Unlocked);
+    if single && !account_entries.is_empty() {
+        // The lock or unlock item comes first
+        menu.extend(account_entries.drain(..1));
+    } else if any_unlocked {
+        menu.push(Entry::Item("Lock All".into(), Events::LockAll));
+    } else {
+        menu.push(Entry::Item("Unlock".into(), Events::UnlockAll));
+    }
+    if any_unlocked {
+        menu.push(Entry::Item("Sync Vault".into(), Events::SyncNow));
+    }
+    if !state.recent.is_empty() {
+        let recent = state
+            .recent
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Entry::Item(name.clone(), Events::Recent(i)))
+            .collect();
+        menu.push(Entry::Submenu("Recent".into(), recent));
+    }
+    menu.push(Entry::Item(
+        "Associate Window with Login...".into(),
+        Events::Associate,
+    ));
+    menu.push(Entry::Checkable(
+        "Pause Autotype".into(),
+        state.paused,
+        Events::TogglePause,
+    ));
+    menu.extend(account_entries);
+    menu.push(Entry::Separator);
+    menu.push(Entry::Item("Exit".into(), Events::Exit));
+    menu
+}
+
+fn account(index: usize) -> Option<crate::config::Account> {
+    CONFIG.read().accounts.get(index).cloned()
+}
+
+fn handle_event(event: Events) {
+    match event {
+        Events::Exit => {
+            info!("Shutting down");
+            exit(0);
+        }
+        Events::SyncNow => {
+            info!("Sync requested");
+            crate::bw_cli::sync()
+        }
+        Events::UnlockAll => {
+            info!("Unlock requested");
+            crate::ensure_unlocked();
+        }
+        Events::LockAll => {
+            info!("Lock requested");
+            crate::lock_vault();
+        }
+        Events::Unlock(i) => {
+            if let Some(account) = account(i) {
+                info!("Unlock of {} requested", account.name);
+                crate::unlock_account(&account);
+            }
+        }
+        Events::Lock(i) => {
+            if let Some(account) = account(i) {
+                info!("Lock of {} requested", account.name);
+                crate::lock_account(&account);
+            }
+        }
+        Events::LogOut(i) => {
+            if let Some(account) = account(i) {
+                crate::log_out(&account);
+            }
+        }
+        Events::SwitchAccount(i) => {
+            if let Some(account) = account(i) {
+                info!("Switching accounts");
+                crate::log_out(&account);
+                crate::unlock_account(&account);
+            }
+        }
+        Events::TogglePause => crate::toggle_pause(),
+        Events::Associate => crate::associate_last_window(),
+        Events::Recent(i) => crate::autotype_recent(i),
+        e => {
+            println!("{:?}", e);
+        }
+    }
+}
+
+/// Shows the tray icon. Blocks forever, so this should be the last thing `main` does.
+pub fn main() {
+    let (s, r) = std::sync::mpsc::channel::<Events>();
+    let (update_s, update_r) = std::sync::mpsc::channel::<Update>();
+    *UPDATES.lock() = Some(update_s);
+
+    std::thread::spawn(move || {
+        refresh();
+        r.iter().for_each(handle_event)
+    });
+
+    backend::run(s, update_r);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(vault_status: VaultStatus) -> Status {
+        Status {
+            server_url: None,
+            last_sync: None,
+            user_email: Some("me@example.com".into()),
+            vault_status,
+        }
+    }
+
+    fn labels(menu: &[Entry]) -> Vec<String> {
+        menu.iter()
+            .filter_map(|entry| match entry {
+                Entry::Info(l) | Entry::Item(l, _) | Entry::Checkable(l, ..) => Some(l.clone()),
+                Entry::Submenu(l, _) => Some(format!("{} >", l)),
+                Entry::Separator => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tooltip_follows_the_state() {
+        let mut state = TrayState::default();
+        assert_eq!(tooltip(&state), "Bitwarden Autotype - Starting");
+        state.apply(Update::Status(vec![status(VaultStatus::Locked)]));
+        assert_eq!(tooltip(&state), "Bitwarden Autotype - Locked");
+        state.apply(Update::Status(vec![status(VaultStatus::Unlocked)]));
+        state.apply(Update::Matches(3));
+        assert_eq!(
+            tooltip(&state),
+            "Bitwarden Autotype - Unlocked (3 matching logins)"
+        );
+        state.apply(Update::Error(Some("bw failed".into())));
+        assert_eq!(tooltip(&state), "Bitwarden Autotype - Unlocked (Error)");
+        state.apply(Update::Paused(true));
+        assert_eq!(tooltip(&state), "Bitwarden Autotype - Paused (Error)");
+    }
+
+    #[test]
+    fn tooltip_counts_unlocked_accounts() {
+        let mut state = TrayState::default();
+        state.apply(Update::Status(vec![
+            status(VaultStatus::Unlocked),
+            status(VaultStatus::Locked),
+            status(VaultStatus::Unauthenticated),
+        ]));
+        assert_eq!(tooltip(&state), "Bitwarden Autotype - 1/3 unlocked");
+    }
+
+    #[test]
+    fn menu_follows_the_vault() {
+        let mut state = TrayState::default();
+        state.apply(Update::Status(vec![status(VaultStatus::Locked)]));
+        let locked = labels(&menu(&state));
+        assert!(locked.contains(&"Logged in as me@example.com".to_string()));
+        assert!(locked.contains(&"Last synced: never".to_string()));
+        assert!(locked.contains(&"Unlock".to_string()));
+        assert!(!locked.contains(&"Sync Vault".to_string()));
+
+        state.apply(Update::Status(vec![status(VaultStatus::Unlocked)]));
+        state.apply(Update::Error(Some("bw failed".into())));
+        let unlocked = labels(&menu(&state));
+        for label in &[
+            "Lock",
+            "Sync Vault",
+            "Log out",
+            "Switch Account",
+            "Error: bw failed",
+        ] {
+            assert!(unlocked.contains(&label.to_string()), "{} missing", label);
+        }
+        assert!(!unlocked.contains(&"Unlock".to_string()));
+    }
+
+    #[test]
+    fn menu_offers_recent_items() {
+        let mut state = TrayState::default();
+        assert!(!labels(&menu(&state)).contains(&"Recent >".to_string()));
+
+        state.apply(Update::Recent(vec!["GitHub".into(), "VPN".into()]));
+        let menu = menu(&state);
+        let recent = menu
+            .iter()
+            .find_map(|entry| match entry {
+                Entry::Submenu(l, children) if l == "Recent" => Some(children),
+                _ => None,
+            })
+            .unwrap();
+        assert!(matches!(
+            recent.as_slice(),
+            [Entry::Item(a, Events::Recent(0)), Entry::Item(b, Events::Recent(1))]
+                if a == "GitHub" && b == "VPN"
+        ));
+    }
+}
@@ -0,0 +1,517 @@
+//! A StatusNotifierItem tray icon with a DBusMenu, as understood by KDE, waybar and GNOME
+//! (with the AppIndicator extension). Everything happens on the session bus, so setting
+//! `DBUS_SESSION_BUS_ADDRESS` points it at a stand-in bus instead.
+
+use super::{Entry, Events, TrayState, Update};
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use zbus::blocking::{Connection, ConnectionBuilder, Proxy};
+use zbus::zvariant::{OwnedObjectPath, OwnedValue, StructureBuilder, Type, Value};
+use zbus::{dbus_interface, SignalContext};
+
+static ITEM_PATH: &str = "/StatusNotifierItem";
+static MENU_PATH: &str = "/MenuBar";
+static ICON_NAME: &str = "bitwarden-autotype";
+static FALLBACK_ICON_NAME: &str = "dialog-password";
+
+/// Width, height and ARGB32 data of an icon
+type Pixmap = (i32, i32, Vec<u8>);
+
+/// What the D-Bus interfaces show, replaced whenever the tray state changes.
+struct Shared {
+    tooltip: String,
+    menu: Vec<Entry>,
+    /// Bumped on every change, so menu hosts know their copy of the layout is stale
+    revision: u32,
+}
+
+struct StatusNotifierItem {
+    shared: Arc<Mutex<Shared>>,
+    icon_theme_path: Option<PathBuf>,
+}
+
+#[dbus_interface(name = "org.kde.StatusNotifierItem")]
+impl StatusNotifierItem {
+    #[dbus_interface(property)]
+    fn category(&self) -> &str {
+        "ApplicationStatus"
+    }
+
+    #[dbus_interface(property)]
+    fn id(&self) -> &str {
+        env!("CARGO_PKG_NAME")
+    }
+
+    #[dbus_interface(property)]
+    fn title(&self) -> &str {
+        super::TOOLTIP
+    }
+
+    #[dbus_interface(property)]
+    fn status(&self) -> &str {
+        "Active"
+    }
+
+    #[dbus_interface(property)]
+    fn icon_name(&self) -> &str {
+        match self.icon_theme_path {
+            Some(_) => ICON_NAME,
+            None => FALLBACK_ICON_NAME,
+        }
+    }
+
+    #[dbus_interface(property)]
+    fn icon_theme_path(&self) -> String {
+        self.icon_theme_path
+            .as_ref()
+            .map(|p| p.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Icon name, icon pixmaps, title and description
+    #[dbus_interface(property)]
+    fn tool_tip(&self) -> (String, Vec<Pixmap>, String, String) {
+        let tooltip = self.shared.lock().tooltip.clone();
+        (String::new(), vec![], super::TOOLTIP.into(), tooltip)
+    }
+
+    #[dbus_interface(property)]
+    fn item_is_menu(&self) -> bool {
+        true
+    }
+
+    #[dbus_interface(property)]
+    fn menu(&self) -> OwnedObjectPath {
+        OwnedObjectPath::try_from(MENU_PATH).unwrap()
+    }
+
+    // Clicking the icon shows the menu (see ItemIsMenu), so there is nothing else to do
+    fn activate(&self, _x: i32, _y: i32) {}
+
+    fn secondary_activate(&self, _x: i32, _y: i32) {}
+
+    fn context_menu(&self, _x: i32, _y: i32) {}
+
+    fn scroll(&self, _delta: i32, _orientation: &str) {}
+
+    #[dbus_interface(signal)]
+    async fn new_tool_tip(ctxt: &SignalContext<'_>) -> zbus::Result<()>;
+}
+
+/// A menu item and its children, as DBusMenu's `(ia{sv}av)`.
+#[derive(Serialize, Type)]
+struct Layout {
+    id: i32,
+    properties: HashMap<String, OwnedValue>,
+    children: Vec<OwnedValue>,
+}
+
+struct DBusMenu {
+    shared: Arc<Mutex<Shared>>,
+    events: Mutex<Sender<Events>>,
+}
+
+#[dbus_interface(name = "com.canonical.dbusmenu")]
+impl DBusMenu {
+    #[dbus_interface(property)]
+    fn version(&self) -> u32 {
+        3
+    }
+
+    #[dbus_interface(property)]
+    fn text_direction(&self) -> &str {
+        "ltr"
+    }
+
+    #[dbus_interface(property)]
+    fn status(&self) -> &str {
+        "normal"
+    }
+
+    #[dbus_interface(property)]
+    fn icon_theme_path(&self) -> Vec<String> {
+        vec![]
+    }
+
+    fn get_layout(
+        &self,
+        parent_id: i32,
+        _recursion_depth: i32,
+        _property_names: Vec<String>,
+    ) -> zbus::fdo::Result<(u32, Layout)> {
+        let shared = self.shared.lock();
+        let layout = if parent_id == 0 {
+            Layout {
+                id: 0,
+                properties: vec![("children-display".to_string(), owned("submenu"))]
+                    .into_iter()
+                    .collect(),
+                children: children_layout(&shared.menu, &mut 1),
+            }
+        } else {
+            subtree_layout(&shared.menu, parent_id, &mut 1).ok_or_else(|| {
+                zbus::fdo::Error::InvalidArgs(format!("No menu item with id {}", parent_id))
+            })?
+        };
+        Ok((shared.revision, layout))
+    }
+
+    fn get_group_properties(
+        &self,
+        ids: Vec<i32>,
+        _property_names: Vec<String>,
+    ) -> Vec<(i32, HashMap<String, OwnedValue>)> {
+        let shared = self.shared.lock();
+        let mut found = vec![];
+        visit(&shared.menu, &mut 1, &mut |id, entry| {
+            if ids.is_empty() || ids.contains(&id) {
+                found.push((id, properties(entry)));
+            }
+        });
+        found
+    }
+
+    fn get_property(&self, id: i32, name: &str) -> zbus::fdo::Result<OwnedValue> {
+        let shared = self.shared.lock();
+        let mut value = None;
+        visit(&shared.menu, &mut 1, &mut |i, entry| {
+            if i == id {
+                value = properties(entry).remove(name);
+            }
+        });
+        value.ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("No {} on item {}", name, id)))
+    }
+
+    fn event(&self, id: i32, event_id: &str, _data: OwnedValue, _timestamp: u32) {
+        if event_id != "clicked" {
+            return;
+        }
+        let shared = self.shared.lock();
+        let mut event = None;
+        visit(&shared.menu, &mut 1, &mut |i, entry| match entry {
+            Entry::Item(_, e) | Entry::Checkable(_, _, e) if i == id => event = Some(*e),
+            _ => {}
+        });
+        if let Some(e) = event {
+            let _ = self.events.lock().send(e);
+        }
+    }
+
+    fn event_group(&self, events: Vec<(i32, String, OwnedValue, u32)>) -> Vec<i32> {
+        for (id, event_id, data, timestamp) in events {
+            self.event(id, &event_id, data, timestamp);
+        }
+        vec![]
+    }
+
+    /// Returns whether the layout needs updating; we always send it in full, so it never does
+    fn about_to_show(&self, _id: i32) -> bool {
+        false
+    }
+
+    fn about_to_show_group(&self, _ids: Vec<i32>) -> (Vec<i32>, Vec<i32>) {
+        (vec![], vec![])
+    }
+
+    #[dbus_interface(signal)]
+    async fn layout_updated(
+        ctxt: &SignalContext<'_>,
+        revision: u32,
+        parent: i32,
+    ) -> zbus::Result<()>;
+}
+
+fn owned<'a, T: Into<Value<'a>>>(value: T) -> OwnedValue {
+    OwnedValue::from(value.into())
+}
+
+fn properties(entry: &Entry) -> HashMap<String, OwnedValue> {
+    // Underscores mark mnemonics in DBusMenu labels, so literal ones have to be doubled
+    let label = |l: &str| owned(l.replace('_', "__"));
+    let properties = match entry {
+        Entry::Info(l) => vec![("label", label(l)), ("enabled", owned(false))],
+        Entry::Item(l, _) => vec![("label", label(l))],
+        Entry::Checkable(l, checked, _) => vec![
+            ("label", label(l)),
+            ("toggle-type", owned("checkmark")),
+            ("toggle-state", owned(*checked as i32)),
+        ],
+        Entry::Submenu(l, _) => vec![("label", label(l)), ("children-display", owned("submenu"))],
+        Entry::Separator => vec![("type", owned("separator"))],
+    };
+    properties
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+}
+
+// Items are numbered depth-first starting at 1 (0 is the root), the same way on every walk,
+// so ids stay valid until the menu changes.
+fn visit<'a>(entries: &'a [Entry], next_id: &mut i32, f: &mut dyn FnMut(i32, &'a Entry)) {
+    for entry in entries {
+        let id = *next_id;
+        *next_id += 1;
+        f(id, entry);
+        if let Entry::Submenu(_, children) = entry {
+            visit(children, next_id, f);
+        }
+    }
+}
+
+fn children_layout(entries: &[Entry], next_id: &mut i32) -> Vec<OwnedValue> {
+    entries
+        .iter()
+        .map(|entry| {
+            let id = *next_id;
+            *next_id += 1;
+            let children = match entry {
+                Entry::Submenu(_, children) => children_layout(children, next_id),
+                _ => vec![],
+            };
+            let structure = StructureBuilder::new()
+                .add_field(id)
+                .add_field(properties(entry))
+                .add_field(children)
+                .build();
+            owned(structure)
+        })
+        .collect()
+}
+
+fn subtree_layout(entries: &[Entry], target: i32, next_id: &mut i32) -> Option<Layout> {
+    for entry in entries {
+        let id = *next_id;
+        *next_id += 1;
+        if let Entry::Submenu(_, children) = entry {
+            if id == target {
+                return Some(Layout {
+                    id,
+                    properties: properties(entry),
+                    children: children_layout(children, next_id),
+                });
+            }
+            if let Some(layout) = subtree_layout(children, target, next_id) {
+                return Some(layout);
+            }
+        } else if id == target {
+            return Some(Layout {
+                id,
+                properties: properties(entry),
+                children: vec![],
+            });
+        }
+    }
+    None
+}
+
+// Icon hosts look up icons by name, so our icon is put somewhere they can find it
+fn install_icon() -> Option<PathBuf> {
+    let dir = dirs::cache_dir()?
+        .join(env!("CARGO_PKG_NAME"))
+        .join("icons");
+    let result = std::fs::create_dir_all(&dir).and_then(|_| {
+        std::fs::write(
+            dir.join(format!("{}.png", ICON_NAME)),
+            include_bytes!("../../assets/icon.png"),
+        )
+    });
+    match result {
+        Ok(_) => Some(dir),
+        Err(e) => {
+            warn!("Failed to install tray icon, using a generic one: {}", e);
+            None
+        }
+    }
+}
+
+fn serve(
+    builder: ConnectionBuilder,
+    s: Sender<Events>,
+    update_r: &Receiver<Update>,
+) -> zbus::Result<()> {
+    let mut state = TrayState::default();
+    let shared = Arc::new(Mutex::new(Shared {
+        tooltip: super::tooltip(&state),
+        menu: super::menu(&state),
+        revision: 1,
+    }));
+
+    let name = format!("org.kde.StatusNotifierItem-{}-1", std::process::id());
+    let item = StatusNotifierItem {
+        shared: shared.clone(),
+        icon_theme_path: install_icon(),
+    };
+    let menu = DBusMenu {
+        shared: shared.clone(),
+        events: Mutex::new(s),
+    };
+    let connection: Connection = builder
+        .name(name.clone())?
+        .serve_at(ITEM_PATH, item)?
+        .serve_at(MENU_PATH, menu)?
+        .build()?;
+
+    let watcher = Proxy::new(
+        &connection,
+        "org.kde.StatusNotifierWatcher",
+        "/StatusNotifierWatcher",
+        "org.kde.StatusNotifierWatcher",
+    )?;
+    watcher.call_method("RegisterStatusNotifierItem", &(name.as_str()))?;
+    info!("Registered tray icon as {}", name);
+
+    let item_context = SignalContext::new(connection.inner(), ITEM_PATH)?;
+    let menu_context = SignalContext::new(connection.inner(), MENU_PATH)?;
+    while let Ok(update) = update_r.recv() {
+        state.apply(update);
+        for update in update_r.try_iter() {
+            state.apply(update);
+        }
+
+        let revision = {
+            let mut shared = shared.lock();
+            shared.tooltip = super::tooltip(&state);
+            shared.menu = super::menu(&state);
+            shared.revision += 1;
+            shared.revision
+        };
+        zbus::block_on(StatusNotifierItem::new_tool_tip(&item_context))?;
+        zbus::block_on(DBusMenu::layout_updated(&menu_context, revision, 0))?;
+    }
+    Ok(())
+}
+
+pub(super) fn run(s: Sender<Events>, update_r: Receiver<Update>) {
+    if let Err(e) = ConnectionBuilder::session().and_then(|builder| serve(builder, s, &update_r)) {
+        error!("Failed to show tray icon: {}", e);
+    }
+    // Hotkeys keep working without a tray icon, so don't take the rest of the program down
+    update_r.iter().for_each(drop);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_bus::TestBus;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+
+    struct Watcher {
+        registered: Mutex<Sender<String>>,
+    }
+
+    #[dbus_interface(name = "org.kde.StatusNotifierWatcher")]
+    impl Watcher {
+        fn register_status_notifier_item(&self, service: &str) {
+            let _ = self.registered.lock().send(service.to_string());
+        }
+    }
+
+    type LayoutReply = (u32, (i32, HashMap<String, OwnedValue>, Vec<OwnedValue>));
+
+    // Ids, labels and toggle states of the top level items
+    fn items(layout: &LayoutReply) -> Vec<(i32, String, Option<i32>)> {
+        let children = &(layout.1).2;
+        children
+            .iter()
+            .map(|child| {
+                let fields = match &**child {
+                    Value::Structure(s) => s.fields(),
+                    v => panic!("Unexpected layout {:?}", v),
+                };
+                let properties = match &fields[1] {
+                    Value::Dict(d) => HashMap::<String, OwnedValue>::try_from(d.clone()).unwrap(),
+                    v => panic!("Unexpected properties {:?}", v),
+                };
+                let label = properties
+                    .get("label")
+                    .map(|l| String::try_from(l.clone()).unwrap())
+                    .unwrap_or_default();
+                let toggle = properties
+                    .get("toggle-state")
+                    .map(|t| i32::try_from(t.clone()).unwrap());
+                (i32::try_from(fields[0].clone()).unwrap(), label, toggle)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn registers_and_serves_the_menu() {
+        let bus = match TestBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let (registered_s, registered_r) = channel();
+        let watcher = bus
+            .builder()
+            .name("org.kde.StatusNotifierWatcher")
+            .unwrap()
+            .serve_at(
+                "/StatusNotifierWatcher",
+                Watcher {
+                    registered: Mutex::new(registered_s),
+                },
+            )
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let (events_s, events_r) = channel();
+        let (update_s, update_r) = channel();
+        let builder = bus.builder();
+        std::thread::spawn(move || serve(builder, events_s, &update_r));
+        let name = registered_r
+            .recv_timeout(Duration::from_secs(10))
+            .expect("The item never registered");
+
+        let menu =
+            Proxy::new(&watcher, name.as_str(), MENU_PATH, "com.canonical.dbusmenu").unwrap();
+        let get_layout = || -> LayoutReply {
+            menu.call("GetLayout", &(0, -1, Vec::<String>::new()))
+                .unwrap()
+        };
+        let layout = get_layout();
+        assert_eq!(layout.0, 1);
+        let entries = items(&layout);
+        assert_eq!(entries[0].1, "Bitwarden Autotype is running");
+        assert_eq!(entries.last().unwrap().1, "Exit");
+        let (pause_id, _, paused) = entries
+            .iter()
+            .find(|(_, l, _)| l == "Pause Autotype")
+            .unwrap();
+        assert_eq!(*paused, Some(0));
+
+        // Updates bump the revision and show up in the layout
+        update_s.send(Update::Paused(true)).unwrap();
+        let mut layout = get_layout();
+        for _ in 0..100 {
+            if layout.0 > 1 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            layout = get_layout();
+        }
+        assert_eq!(layout.0, 2);
+        let paused = items(&layout)
+            .into_iter()
+            .find(|(id, ..)| id == pause_id)
+            .unwrap()
+            .2;
+        assert_eq!(paused, Some(1));
+
+        // Clicking an item sends its event
+        let () = menu
+            .call("Event", &(*pause_id, "clicked", Value::from(0i32), 0u32))
+            .unwrap();
+        assert_eq!(
+            events_r.recv_timeout(Duration::from_secs(10)).unwrap(),
+            Events::TogglePause
+        );
+    }
+}
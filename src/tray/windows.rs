@@ -0,0 +1,70 @@
+use super::{Entry, Events, TrayState, Update};
+use log::warn;
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::Duration;
+use trayicon::{Icon, MenuBuilder, MenuItem, TrayIconBuilder};
+use winapi::_core::mem::MaybeUninit;
+use winapi::_core::ptr;
+use winapi::um::winuser;
+
+fn info_item(name: &str) -> MenuItem<Events> {
+    MenuItem::Item {
+        id: Events::Dummy,
+        name: name.to_string(),
+        disabled: true,
+        icon: None,
+    }
+}
+
+fn menu_builder(entries: &[Entry]) -> MenuBuilder<Events> {
+    entries
+        .iter()
+        .fold(MenuBuilder::new(), |menu, entry| match entry {
+            Entry::Info(name) => menu.with(info_item(name)),
+            Entry::Item(name, id) => menu.item(name, *id),
+            Entry::Checkable(name, checked, id) => menu.checkable(name, *checked, *id),
+            Entry::Submenu(name, children) => menu.submenu(name, menu_builder(children)),
+            Entry::Separator => menu.separator(),
+        })
+}
+
+pub(super) fn run(s: Sender<Events>, update_r: Receiver<Update>) {
+    let icon_bytes = include_bytes!("../../assets/icon.ico");
+    let icon = Icon::from_buffer(icon_bytes, None, None).unwrap();
+
+    let mut state = TrayState::default();
+    let mut tray_icon = TrayIconBuilder::new()
+        .icon(icon)
+        .sender(s)
+        .tooltip(&super::tooltip(&state))
+        .menu(menu_builder(&super::menu(&state)))
+        .build()
+        .unwrap();
+
+    // The tray icon can only be touched from this thread, so instead of blocking on
+    // GetMessageW we alternate between window messages and queued updates.
+    loop {
+        unsafe {
+            let mut msg = MaybeUninit::uninit();
+            while winuser::PeekMessageW(msg.as_mut_ptr(), ptr::null_mut(), 0, 0, winuser::PM_REMOVE)
+                > 0
+            {
+                winuser::TranslateMessage(msg.as_ptr());
+                winuser::DispatchMessageW(msg.as_ptr());
+            }
+        }
+
+        let mut changed = false;
+        for update in update_r.try_iter() {
+            state.apply(update);
+            changed = true;
+        }
+        if changed {
+            tray_icon
+                .set_menu(&menu_builder(&super::menu(&state)))
+                .and_then(|_| tray_icon.set_tooltip(&super::tooltip(&state)))
+                .unwrap_or_else(|e| warn!("Failed to update tray icon: {:?}", e));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
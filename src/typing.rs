@@ -1,24 +1,21 @@
 use log::error;
 use regex::{Match, Regex};
 use serde::{Deserialize, Serialize};
-use std::panic::catch_unwind;
 use std::time::Duration;
-use winput::{send, Vk};
 
 static TAB_REGEX: &str = r"\{TAB\}";
 static ENTER_REGEX: &str = r"\{ENTER\}";
 static SLEEP_REGEX: &str = r"\{SLEEP=(\d+)\}";
-static DEFAULT_SLEEP_MILLIS: u64 = 20;
 
 pub fn send_raw_string(string: String) {
+    let delay = Duration::from_millis(crate::config::CONFIG.read().key_delay_millis);
     for cmd in to_command_stream(string) {
-        std::thread::sleep(Duration::from_millis(DEFAULT_SLEEP_MILLIS));
+        std::thread::sleep(delay);
         handle_cmd(cmd)
     }
 }
 
 pub fn to_command_stream(string: String) -> Vec<Command> {
-    let string = string;
     let mut commands_positions: Vec<(usize, Command)> = string
         .char_indices()
         .map(|(i, c)| (i, Command::Char(c)))
@@ -93,7 +90,11 @@ pub fn handle_cmd(cmd: Command) {
     }
 }
 
+#[cfg(windows)]
 fn send_char(c: char) {
+    use std::panic::catch_unwind;
+    use winput::{send, Vk};
+
     if catch_unwind(|| match c {
         '\t' => send(Vk::Tab),
         '\n' => send(Vk::Enter),
@@ -104,3 +105,11 @@ fn send_char(c: char) {
         error!("Failed to send keystroke for character");
     }
 }
+
+#[cfg(not(windows))]
+fn send_char(_c: char) {
+    use std::sync::Once;
+
+    static WARNING: Once = Once::new();
+    WARNING.call_once(|| error!("Typing isn't supported on this platform"));
+}
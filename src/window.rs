@@ -0,0 +1,194 @@
+//! Other programs' windows: which one is focused, what it is, and focusing it again.
+
+/// A window, as an HWND on Windows. Kept as an integer so it can be sent between threads.
+pub type WindowHandle = isize;
+
+#[cfg(windows)]
+pub use self::windows::*;
+
+#[cfg(not(windows))]
+pub use self::unsupported::*;
+
+use log::warn;
+use std::time::{Duration, Instant};
+
+/// Focuses the window again after one of ours took the focus, and waits until it has it.
+/// Returns false if it never got the focus, in which case nothing should be typed.
+pub fn refocus(window: WindowHandle) -> bool {
+    set_foreground(window);
+    let start = Instant::now();
+    while foreground() != window {
+        if start.elapsed() > Duration::from_secs(1) {
+            warn!("Failed to focus the window to autotype into again");
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    // Give the window a moment to actually receive keyboard focus
+    std::thread::sleep(Duration::from_millis(100));
+    true
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::WindowHandle;
+    use lazy_static::lazy_static;
+    use log::{error, info, warn};
+    use parking_lot::Mutex;
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStringExt;
+    use std::path::PathBuf;
+    use winapi::_core::{mem, ptr};
+    use winapi::shared::minwindef::DWORD;
+    use winapi::shared::ntdef::{FALSE, LONG};
+    use winapi::shared::windef::{HWINEVENTHOOK, HWND};
+    use winapi::um::processthreadsapi::OpenProcess;
+    use winapi::um::psapi::GetProcessImageFileNameW;
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+    use winapi::um::winuser::{
+        GetClassNameW, GetForegroundWindow, GetMessageW, GetWindowTextLengthW, GetWindowTextW,
+        GetWindowThreadProcessId, SetForegroundWindow, SetWinEventHook, EVENT_SYSTEM_FOREGROUND,
+        WINEVENT_OUTOFCONTEXT,
+    };
+
+    lazy_static! {
+        static ref ON_FOREGROUND_CHANGE: Mutex<Option<fn(WindowHandle)>> = Mutex::new(None);
+    }
+
+    pub fn foreground() -> WindowHandle {
+        unsafe { GetForegroundWindow() as WindowHandle }
+    }
+
+    pub fn set_foreground(window: WindowHandle) {
+        unsafe { SetForegroundWindow(window as HWND) };
+    }
+
+    pub fn title(window: WindowHandle) -> String {
+        let window = window as HWND;
+        let title_len = unsafe { GetWindowTextLengthW(window) } + 1; // Get the title length (+1 to be sure)
+
+        let mut buffer: Vec<u16> = Vec::with_capacity(title_len as usize); // Create a buffer that windows can fill
+        let read_len = unsafe { GetWindowTextW(window, buffer.as_mut_ptr(), title_len) }; // Tell windows to fill the buffer
+
+        // Tell the buffer how much has been read into it, lest it still thinks it's empty, resulting in an empty string
+        unsafe { buffer.set_len(read_len as usize) };
+        String::from_utf16_lossy(buffer.as_slice())
+    }
+
+    pub fn executable(window: WindowHandle) -> String {
+        let mut pid = 0;
+        unsafe { GetWindowThreadProcessId(window as HWND, &mut pid) }; // Get the process id
+        let psapi_handle =
+            unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE as i32, pid) }; // Get a PSAPI handle, limited information permission is sufficient
+
+        let mut buffer = [0; 1024]; // Same as above, except we have to guess the capacity, and we have to use a slice for... reasons?
+        let read_len = unsafe {
+            GetProcessImageFileNameW(psapi_handle, buffer.as_mut_ptr(), buffer.len() as DWORD)
+        };
+        let executable_path: PathBuf = OsString::from_wide(&buffer[..read_len as usize]).into();
+
+        // Empty if the process couldn't be queried, e.g. because it runs elevated
+        executable_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    // Our own windows (like the login picker) steal focus, but never need autotyping
+    pub fn is_own(window: WindowHandle) -> bool {
+        let mut pid = 0;
+        unsafe { GetWindowThreadProcessId(window as HWND, &mut pid) };
+        pid == std::process::id()
+    }
+
+    pub fn is_taskbar(window: WindowHandle) -> bool {
+        let mut buffer = [0; 64];
+        let len =
+            unsafe { GetClassNameW(window as HWND, buffer.as_mut_ptr(), buffer.len() as i32) };
+        let class = String::from_utf16_lossy(&buffer[..len as usize]);
+        // The taskbar, its overflow area and secondary monitors' taskbars
+        [
+            "Shell_TrayWnd",
+            "NotifyIconOverflowWindow",
+            "Shell_SecondaryTrayWnd",
+        ]
+        .contains(&class.as_str())
+    }
+
+    /// Calls `on_change` whenever another window is focused. Blocks forever, so this should be
+    /// run in its own thread.
+    pub fn watch_foreground(on_change: fn(WindowHandle)) {
+        *ON_FOREGROUND_CHANGE.lock() = Some(on_change);
+        let hook = unsafe {
+            SetWinEventHook(
+                EVENT_SYSTEM_FOREGROUND,
+                EVENT_SYSTEM_FOREGROUND,
+                ptr::null_mut(),
+                Some(on_foreground_change),
+                0,
+                0,
+                WINEVENT_OUTOFCONTEXT,
+            )
+        };
+        if hook.is_null() {
+            error!("Failed to hook into foreground window changes");
+            return;
+        }
+        info!("Watching foreground window changes");
+
+        // Out-of-context hooks are delivered through this thread's message queue
+        let mut msg = unsafe { mem::zeroed() };
+        loop {
+            if unsafe { GetMessageW(&mut msg, ptr::null_mut(), 0, 0) } == 0 {
+                warn!("Failed to receive message");
+            }
+        }
+    }
+
+    unsafe extern "system" fn on_foreground_change(
+        _hook: HWINEVENTHOOK,
+        _event: DWORD,
+        window: HWND,
+        _id_object: LONG,
+        _id_child: LONG,
+        _event_thread: DWORD,
+        _event_time: DWORD,
+    ) {
+        if let Some(on_change) = *ON_FOREGROUND_CHANGE.lock() {
+            on_change(window as WindowHandle);
+        }
+    }
+}
+
+// Elsewhere, windows aren't followed yet, so everything acts as if no window was focused
+#[cfg(not(windows))]
+mod unsupported {
+    use super::WindowHandle;
+    use log::warn;
+
+    pub fn foreground() -> WindowHandle {
+        0
+    }
+
+    pub fn set_foreground(_window: WindowHandle) {}
+
+    pub fn title(_window: WindowHandle) -> String {
+        String::new()
+    }
+
+    pub fn executable(_window: WindowHandle) -> String {
+        String::new()
+    }
+
+    pub fn is_own(_window: WindowHandle) -> bool {
+        false
+    }
+
+    pub fn is_taskbar(_window: WindowHandle) -> bool {
+        false
+    }
+
+    pub fn watch_foreground(_on_change: fn(WindowHandle)) {
+        warn!("Following the focused window isn't supported on this platform");
+    }
+}